@@ -1,18 +1,29 @@
-use clap::builder::ArgPredicate;
-use clap::Parser;
-use std::path::PathBuf;
+use clap::builder::{ArgPredicate, PossibleValue, PossibleValuesParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use crossterm::tty::IsTty;
+use isolang::Language;
+use itertools::Itertools;
+use std::ffi::OsString;
+use std::path::{Component, PathBuf};
 use std::thread::available_parallelism;
 
 /// AV1 Encoding Script using VS filters, av1an, opusenc, grav1synth, and mkvmerge
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None, arg_required_else_help(true))]
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None, arg_required_else_help(true), args_override_self = true)]
 pub struct Args {
-    /// Input directory containing video files
-    #[arg(short, long)]
+    /// Input directory containing video files, an http(s) URL of a single video is downloaded first
+    #[arg(short, long, required = false, default_value_if("input_file", ArgPredicate::IsPresent, Some(".")))]
     pub input_directory: PathBuf,
-    /// Output directory for processed video files
+    /// Process only this video file, caches and sidecars are placed next to it. An image sequence is given as a pattern like frame_%06d.png and needs --fps-override
+    #[arg(long, conflicts_with = "input_directory", default_value = None)]
+    pub input_file: Option<PathBuf>,
+    /// Output directory for processed video files, - writes the muxed file of a single source to stdout
     #[arg(short, long)]
     pub output_directory: PathBuf,
+    /// Subdirectory of the output directory each episode goes to, {name}, {season} and {group} are filled in, like "{name}/Season {season}"
+    #[arg(long, default_value = None)]
+    pub output_template: Option<String>,
     /// Group name
     #[arg(short, long, default_value_t = String::from("Group"))]
     pub group: String,
@@ -40,6 +51,12 @@ pub struct Args {
     /// Pauses operation to review and manually edit VapourSynth scripts and tags per episode
     #[arg(short, long, num_args = 0, default_value_t = false)]
     pub review: bool,
+    /// Never wait for input, stalls that would prompt are retried or abort with an error
+    #[arg(long, num_args = 0, conflicts_with = "review", default_value_t = false)]
+    pub non_interactive: bool,
+    /// Continue on its own when a --review prompt gets no answer within this many seconds
+    #[arg(long, requires = "review", default_value = None)]
+    pub review_timeout: Option<u64>,
     /// Skip creating VapourSynth filters
     #[arg(long, num_args = 0, default_value_t = false)]
     pub no_filter: bool,
@@ -87,24 +104,79 @@ pub struct Args {
     /// Deband strength
     #[arg(long, default_value_t = 64)]
     pub deband: u8,
+    /// Skip deband
+    #[arg(long, num_args = 0, conflicts_with = "retinex", default_value_t = false)]
+    pub no_deband: bool,
     /// Use retinex mask for debanding
     #[arg(long, num_args = 0, default_value_t = false)]
     pub retinex: bool,
-    /// Number of av1an workers
-    #[arg(short, long, default_value_t = available_parallelism().unwrap().get() as u8)]
-    pub workers: u8,
+    /// Sigma of the retinex edge mask, higher protects fewer edges [default: vsdeband's]
+    #[arg(long, requires = "retinex", default_value = None)]
+    pub retinex_sigma: Option<f32>,
+    /// RemoveGrain mode used to clean the retinex edge mask
+    #[arg(long, requires = "retinex", default_value_t = 0)]
+    pub retinex_rg_mode: u8,
+    /// Number of av1an workers, "auto" picks a count from the source resolution and system memory
+    #[arg(short, long, value_parser = parse_workers, default_value_t = available_parallelism().unwrap().get().to_string())]
+    pub workers: String,
     /// Max cache size per vspipe/worker in GB
     #[arg(short, long, default_value_t = 1)]
     pub mem: u8,
+    /// Max cache size for the scene detection script in GB [default: --mem]
+    #[arg(long, default_value = None)]
+    pub sc_mem: Option<u8>,
+    /// VapourSynth core threads for generated scripts and metric calculation [default: all threads]
+    #[arg(long, default_value = None)]
+    pub vs_threads: Option<u16>,
+    /// Directory of VapourSynth plugins to load when they aren't installed globally
+    #[arg(long, default_value = None)]
+    pub vs_plugin_dir: Option<PathBuf>,
     /// For chunking and VS scripts
     #[arg(long = "source_filter", value_parser(["lsmash","dgdecnv","bestsource"]), default_value = "bestsource")]
     pub source_filter: String,
+    /// Av1an chunk method [default: --source_filter]
+    #[arg(long, value_parser(["segment","select","hybrid","lsmash","ffms2","dgdecnv","bestsource"]), default_value = None)]
+    pub chunk_method: Option<String>,
+    /// Av1an concatenation method
+    #[arg(long, value_parser(["mkvmerge","ffmpeg","ivf"]), default_value = "mkvmerge")]
+    pub concat: String,
+    /// Frame rate to use instead of the source's, for sources with broken frame rate metadata (e.g. 24000/1001)
+    #[arg(long, value_parser = parse_frame_rate, default_value = None)]
+    pub fps_override: Option<String>,
+    /// Convert every episode to this frame rate (e.g. 24000/1001), for batches that mix sources of different rates. 30000/1001 to 24000/1001 is treated as telecine and goes through VFM/VDecimate, anything else drops or repeats frames
+    #[arg(long, value_parser = parse_frame_rate, default_value = None)]
+    pub target_fps: Option<String>,
     /// Video encoder
     #[arg(short, long, value_parser(["svt-av1","rav1e"]), default_value = "svt-av1")]
     pub encoder: String,
-    /// Pixel format
-    #[arg(long, default_value_t = String::from("yuv420p10le"))]
-    pub pixel_format: String,
+    /// SVT-AV1 fork of SvtAv1EncApp, PSY-only parameters are left out for mainline, auto asks SvtAv1EncApp --version
+    #[arg(long, value_parser(["auto","psy","mainline"]), default_value = "auto")]
+    pub svt_flavor: String,
+    /// Leave out the anime tuning of svt-av1 (tune, sharpness, variance boost, luma bias and disabled temporal filtering) for SVT's own defaults, for live action and grainy sources.
+    /// Can't be combined with --temporal-filtering
+    #[arg(long, num_args = 0, conflicts_with = "temporal_filtering", default_value_t = false)]
+    pub no_psy: bool,
+    /// Pixel format [default: yuv420p/yuv420p10le/yuv420p12le, from --output-depth]
+    #[arg(long, default_value = None)]
+    pub pixel_format: Option<String>,
+    /// Bit depth the filter scripts dither down to, 12 needs rav1e
+    #[arg(long, value_parser(["8","10","12"]), default_value = "10")]
+    pub output_depth: String,
+    /// Encode 4:2:2 and 4:4:4 sources with their own chroma subsampling instead of 4:2:0, needs rav1e
+    #[arg(long, num_args = 0, conflicts_with = "pixel_format", default_value_t = false)]
+    pub match_chroma: bool,
+    /// Exit instead of warning when the output has less chroma resolution than the source
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub strict: bool,
+    /// Chroma subsampling picked by --match-chroma for the current source
+    #[arg(skip)]
+    pub output_chroma: Option<String>,
+    /// Tonemap HDR (PQ/HLG) sources to SDR BT.709 with vs-placebo
+    #[arg(long, num_args = 0, conflicts_with = "no_filter", default_value_t = false)]
+    pub tonemap: bool,
+    /// libplacebo colorspace of the current source when --tonemap applies to it
+    #[arg(skip)]
+    pub tonemap_from: Option<u8>,
     /// Quality setting [default: 100 (rav1e)/40 (svt-av1)]
     #[arg(
         short,
@@ -123,20 +195,83 @@ pub struct Args {
         visible_alias = "preset"
     )]
     pub speed: u8,
+    /// Target average bitrate in kbps, replaces constant quality and disables target quality
+    #[arg(long, conflicts_with = "target_quality", default_value = None)]
+    pub bitrate: Option<u32>,
     /// rav1e-only setting
     #[arg(short, long, default_value_t = 8)]
     pub tiles: u8,
+    /// Tile rows, overrides --tiles for rav1e (rav1e takes a power of 2, svt-av1 takes log2). More tiles use more threads at a small efficiency cost
+    #[arg(long, default_value = None)]
+    pub tile_rows: Option<u8>,
+    /// Tile columns, overrides --tiles for rav1e (rav1e takes a power of 2, svt-av1 takes log2). More tiles use more threads at a small efficiency cost
+    #[arg(long, default_value = None)]
+    pub tile_cols: Option<u8>,
+    /// svt-av1 deblocking filter (0 = off, 1 = on, 2 = accurate)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=2), default_value_t = 2)]
+    pub dlf: u8,
+    /// svt-av1 CDEF, off by default for anime but helps noisy/grainy live action
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=1), default_value_t = 0)]
+    pub cdef: u8,
+    /// svt-av1 loop restoration, off by default for anime but helps noisy/grainy live action
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=1), default_value_t = 0)]
+    pub restoration: u8,
+    /// svt-av1 temporal filtering, off by default since it smears grain and fine detail
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=1), default_value_t = 0)]
+    pub temporal_filtering: u8,
     /// Manually set extra encoder arguments, includes zone overrides
     #[arg(short, num_args = 1, allow_hyphen_values = true, long, default_value = None)]
     pub parameters: Option<String>,
+    /// Extra av1an arguments appended after the ones grav1an sets, split like a shell would, e.g. "--chunk-order random"
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_shell_words, default_value = None)]
+    pub av1an_args: Option<String>,
+    /// Av1an scenes file to use instead of scene detection, set it in a .grav1an.toml sidecar for per-episode files
+    #[arg(long, default_value = None)]
+    pub scenes: Option<PathBuf>,
+    /// Keyframe at least every this many frames for seeking and streaming, av1an then cuts fixed length chunks instead of detecting scenes. 0 keeps keyframes at scene changes only
+    #[arg(long, conflicts_with = "scenes", default_value_t = 0)]
+    pub keyint: u32,
     /// Only use 1-pass encoding and static quality
-    #[arg(long, num_args = 0, default_value_t = false)]
+    #[arg(long, num_args = 0, default_value_t = false, default_value_if("bitrate", ArgPredicate::IsPresent, "true"))]
     pub single_pass: bool,
-    /// Adjust quality per scene with multipass encoding to target mean SSIMU2 score
+    /// Adjust quality per scene with multipass encoding to target SSIMU2 score, see --target-metric
     #[arg(long, default_value_t = 70.0)]
     pub target_quality: f32,
+    /// Per-scene SSIMU2 statistic fitted against --target-quality
+    #[arg(long, value_parser(["mean","median","p5","p16"]), default_value = "p16")]
+    pub target_metric: String,
+    /// Minimum 5th percentile SSIMU2 score per scene, lowers the Q/crf of scenes with bad worst frames
+    #[arg(long, conflicts_with = "single_pass", default_value = None)]
+    pub min_frame_quality: Option<f32>,
+    /// Largest Q/crf difference allowed between consecutive scenes, off by default
+    #[arg(long, conflicts_with = "single_pass", default_value = None)]
+    pub max_scene_q_delta: Option<f32>,
+    /// Averages each scene's Q/crf with this many scenes on either side, smooths out quality pumping
+    #[arg(long, conflicts_with = "single_pass", value_parser = clap::value_parser!(u8).range(1..), default_value = None)]
+    pub q_smooth: Option<u8>,
     #[arg(short, long, default_value_t = 10)]
     pub cycle: u8,
+    /// Roughly how many frames to score per probe, sets --cycle from the source length
+    #[arg(long, conflicts_with = "cycle", value_parser = clap::value_parser!(u32).range(1..), default_value = None)]
+    pub probe_frames: Option<u32>,
+    /// Encode only this many scenes spread over the range of difficulty into a _sample.mkv, for checking filters, grain and zone overrides
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), default_value = None)]
+    pub sample_scenes: Option<u32>,
+    /// Only detect scenes and encode this many of them at --quantizer, then print the expected encode time and size of each episode and exit
+    #[arg(long, num_args = 0..=1, default_missing_value = "10", conflicts_with = "sample_scenes", value_parser = clap::value_parser!(u32).range(1..), default_value = None)]
+    pub estimate: Option<u32>,
+    /// Reuse the probed quality curve of a similar earlier episode (same group, resolution and length) with a single verification probe, faster but less accurate
+    #[arg(long, num_args = 0, conflicts_with = "single_pass", default_value_t = false)]
+    pub share_probes: bool,
+    /// Number of target quality probes to encode and score at the same time, each runs its own av1an with --workers
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=4), default_value_t = 1)]
+    pub probe_jobs: u8,
+    /// Encoder speed of the target quality probes [default: 10 (rav1e)/8 (svt-av1)]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=13), default_value_t = 8, default_value_if("encoder", "rav1e", "10"))]
+    pub probe_speed: u8,
+    /// Multiples of --quantizer-calc around --quantizer that are probed, at least 4 different ones
+    #[arg(long, value_delimiter = ',', allow_negative_numbers = true, default_value = "2,1,-1,-2")]
+    pub probe_points: Vec<i8>,
     /// Q/crf range for target quality calculations [default: 30 (rav1e)/7.5 (svt-av1)]
     #[arg(
         long,
@@ -144,57 +279,557 @@ pub struct Args {
         default_value_if("encoder", "rav1e", "30")
     )]
     pub quantizer_calc: f32,
-    /// Q/crf range allowed for final pass [default: [40,160] (rav1e)/[25,55] (svt-av1)]
+    /// Lowest Q/crf allowed for final pass [default: 40 (rav1e)/25 (svt-av1)]
     #[arg(long, default_value = None)]
-    pub quantizer_range: Option<String>, // ARGHHHHH clap has no support for conditional default valueS, this SHOULDVE been a (f32, f32), but clap doesnt have default_values_if
+    pub q_min: Option<f32>,
+    /// Highest Q/crf allowed for final pass [default: 160 (rav1e)/55 (svt-av1)]
+    #[arg(long, default_value = None)]
+    pub q_max: Option<f32>,
+    /// Deprecated, use --q-min and --q-max instead
+    #[arg(long, value_parser = parse_quantizer_range, default_value = None)]
+    pub quantizer_range: Option<[f32; 2]>,
     /// Skip FGS
     #[arg(long, num_args = 0, default_value_t = false)]
     pub no_grain: bool,
     /// Estimated FGS
     #[arg(long, num_args = 0, default_value_t = false)]
     pub diff_grain: bool,
-    /// Lehmer merge 2nd source for FGS
+    /// Lossless encoder used for the diff grain reference
+    #[arg(long, value_parser(["x264","x265"]), default_value = "x264")]
+    pub grain_ref_encoder: String,
+    /// Encoder parameters for the diff grain reference, e.g. "--preset ultrafast -q 0" [default: "-q 0" (x264)/"--lossless" (x265)]
+    #[arg(long, allow_hyphen_values = true, default_value = None)]
+    pub grain_ref_params: Option<String>,
+    /// Lehmer merge 2nd source for FGS, only supports exactly 2 sources so the best matching 2nd source video is used
     #[arg(
         long,
-        requires = "src2_directory",
+        requires = "src2",
         num_args = 0,
         default_value_t = false
     )]
     pub lehmer_merge: bool,
+    /// Box blur passes for the lehmer merge lowpass
+    #[arg(long, requires = "lehmer_merge", default_value_t = 3)]
+    pub merge_passes: u8,
     /// Grain intensity as ISO value, --chroma optional
     #[arg(long, default_value_t = 400)]
     pub photon_noise: u16,
+    /// Synthesize grain on the chroma planes too (grav1synth --chroma, av1an --chroma-noise)
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub grain_chroma: bool,
+    /// Extra arguments for grav1synth generate beyond --iso and --chroma, quoted as one value
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_shell_words, conflicts_with = "diff_grain", default_value = None)]
+    pub grain_params: Option<String>,
+    /// Grain synthesis backend, native has av1an apply --photon-noise in the final encode instead of a separate grav1synth pass
+    #[arg(long, value_parser(["grav1synth","native"]), conflicts_with = "diff_grain", default_value = "grav1synth")]
+    pub grain_backend: String,
+    /// Per scene photon noise from the grain measured against the denoised source, scenes below LOW (in 8 bit levels) get none and above HIGH get the full --photon-noise
+    #[arg(
+        long,
+        value_name = "LOW,HIGH",
+        value_parser = parse_grain_thresholds,
+        num_args = 0..=1,
+        default_missing_value = "0.5,2",
+        conflicts_with_all = ["diff_grain", "no_grain", "single_pass"],
+        default_value = None
+    )]
+    pub adaptive_grain: Option<[f64; 2]>,
     /// Raws source
     #[arg(long, default_value_t = String::from("WEB"))]
     pub raws: String,
-    /// Audio source, 1, 2, or both
-    #[arg(long, value_parser(["1","2","both"]), requires_ifs = [("both","src2_directory"),("2","src2_directory")], default_value = "1")]
+    /// Kind of source like "BD Remux" or "WEB-DL", replaces --raws and is added to the file name
+    #[arg(long, default_value = None)]
+    pub source_type: Option<String>,
+    /// Platform the source came from like AMZN or CR, added after the source type
+    #[arg(long, default_value = None)]
+    pub source_platform: Option<String>,
+    /// Audio source, 1, 2, or both, 0 or none leaves audio out
+    #[arg(long, value_parser(["0","none","1","2","both"]), requires_ifs = [("both","src2"),("2","src2")], default_value = "1")]
     pub audio: String,
-    /// Subtitles source, 1, 2, or both
-    #[arg(long, value_parser(["1","2","both"]), requires_ifs = [("both","src2_directory"),("2","src2_directory")], default_value = "1")]
+    /// Subtitles source, 1, 2, or both, 0 or none leaves subtitles out
+    #[arg(long, value_parser(["0","none","1","2","both"]), requires_ifs = [("both","src2"),("2","src2")], default_value = "1")]
     pub subs: String,
+    /// Source whose audio track wins when duplicate tracks are otherwise equal, used with --audio both
+    #[arg(long, value_parser(["source1","source2"]), default_value = "source2")]
+    pub audio_prefer: String,
+    /// Audio codecs from most to least preferred when choosing between duplicate tracks, comma separated, pcm covers every pcm_* format
+    #[arg(long, value_delimiter = ',', default_value = "mlp,truehd,flac,pcm,eac3,aac,opus,ac3,vorbis,mp3,mp2,mp1")]
+    pub audio_codec_priority: Vec<String>,
+    /// Subtitle codecs from most to least preferred when choosing between duplicate tracks, comma separated
+    #[arg(long, value_delimiter = ',', default_value = "ass,subrip,hdmv_pgs_subtitle")]
+    pub sub_codec_priority: Vec<String>,
     /// Input directory containing 2nd sources
-    #[arg(long, value_enum, default_value = None)]
+    #[arg(long, value_enum, group = "src2", default_value = None)]
     pub src2_directory: Option<PathBuf>,
+    /// Part of the file name that picks the 2nd source when several in --src2-directory match an episode, like a release group
+    #[arg(long, requires = "src2_directory", default_value = None)]
+    pub src2_prefer: Option<String>,
+    /// 2nd source for --input-file, used without matching its name or episode
+    #[arg(long, group = "src2", default_value = None)]
+    pub src2_file: Option<PathBuf>,
     /// Manually set offset for 2nd sources in milliseconds
     #[arg(long, allow_hyphen_values = true, default_value_t = 0)]
     pub sync: i32,
+    /// Leave out the 2nd source's subtitles when its detected offset is larger than this many ms, as it's likely a different cut that subtitles can't follow
+    #[arg(long, default_value = None)]
+    pub subtitle_sync_tolerance: Option<u32>,
+    /// How 2nd source offsets are detected, video uses ffmpeg's signature filter, audio cross-correlates the first audio tracks
+    #[arg(long, value_parser(["video","audio"]), default_value = "video")]
+    pub sync_method: String,
+    /// Detection mode of the signature filter used for video sync
+    #[arg(long, value_parser(["fast","full"]), default_value = "fast")]
+    pub sync_detect_mode: String,
+    /// Threshold of matching frames for video sync (signature filter th_xh), raise it for sources with different logos or overlays
+    #[arg(long, default_value_t = 50)]
+    pub sync_threshold: u32,
+    /// Keep the input order of audio and subtitle tracks instead of sorting by language
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub preserve_track_order: bool,
+    /// mkvmerge compression of the video track, mkvmerge's default is none (header removal is no longer offered by mkvmerge)
+    #[arg(long, value_parser(["none","zlib"]), default_value = None)]
+    pub video_compression: Option<String>,
+    /// mkvmerge compression of the audio tracks, mkvmerge's default is none
+    #[arg(long, value_parser(["none","zlib"]), default_value = None)]
+    pub audio_compression: Option<String>,
+    /// mkvmerge compression of the subtitle tracks, none for players that can't read zlib compressed subtitles
+    #[arg(long, value_parser(["none","zlib"]), default_value = "zlib")]
+    pub sub_compression: String,
+    /// Make the first audio track in this language (like jpn or ja) the default one, instead of leaving it to mkvmerge
+    #[arg(long, value_parser = parse_language, default_value = None)]
+    pub default_audio_lang: Option<String>,
+    /// Make the first subtitle track in this language (like eng or en) the default one, instead of leaving it to mkvmerge
+    #[arg(long, value_parser = parse_language, default_value = None)]
+    pub default_sub_lang: Option<String>,
     /// Skip audio re-encoding
     #[arg(long, num_args = 0, default_value_t = false)]
     pub original_audio: bool,
-    /// Choose which library is used to calculate SSIMULACRA2 scores
-    #[arg(long, value_parser(["vszip", "ssimulacra2_rs"]), default_value_t = String::from("vszip"))]
+    /// Keep the audio track titles of the source instead of naming them after their language, untitled tracks still get the language name
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub keep_original_audio_names: bool,
+    /// Path to SvtAv1EncApp, must keep its file name for av1an to find it
+    #[arg(long, default_value = None)]
+    pub svt_bin: Option<PathBuf>,
+    /// Path to rav1e, must keep its file name for av1an to find it
+    #[arg(long, default_value = None)]
+    pub rav1e_bin: Option<PathBuf>,
+    /// Path to ffmpeg
+    #[arg(long, default_value = None)]
+    pub ffmpeg_bin: Option<PathBuf>,
+    /// Path to av1an
+    #[arg(long, default_value = None)]
+    pub av1an_bin: Option<PathBuf>,
+    /// Path to mkvmerge
+    #[arg(long, default_value = None)]
+    pub mkvmerge_bin: Option<PathBuf>,
+    /// Path to grav1synth
+    #[arg(long, default_value = None)]
+    pub grav1synth_bin: Option<PathBuf>,
+    /// Choose which library is used to calculate SSIMULACRA2 scores, av-metrics is another name for ssimulacra2_rs
+    #[arg(
+        long,
+        alias = "ssimu2-backend",
+        value_parser = PossibleValuesParser::new([PossibleValue::new("vszip"), PossibleValue::new("ssimulacra2_rs").alias("av-metrics")])
+            .map(|algo| if algo == "av-metrics" { String::from("ssimulacra2_rs") } else { algo }),
+        default_value_t = String::from("vszip")
+    )]
     pub ssimu2_algo: String,
+    /// Only score about this many evenly spaced frames of each probe instead of all of them
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), default_value = None)]
+    pub metric_sample: Option<u32>,
+    /// Score every n-th frame of each probe, on top of the --cycle decimation
+    #[arg(long, conflicts_with = "metric_sample", value_parser = clap::value_parser!(u16).range(1..), default_value_t = 1)]
+    pub metric_stride: u16,
+    /// Print more details while working, like how many frames the SSIMULACRA 2 scoring skipped with --metric-sample or --metric-stride
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub verbose: bool,
+    /// Also write messages, warnings and the commands that are run to this file with timestamps
+    #[arg(long, default_value = None)]
+    pub log_file: Option<PathBuf>,
+    /// Draw progress bars with plain ASCII characters, on by default for consoles without UTF-8
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub ascii_progress: bool,
+    /// Draw progress bars without colors, also set by the NO_COLOR environment variable
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub no_color: bool,
+    /// Remove av1an temp directories once their encode is finalized, outputs and caches are kept
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub cleanup_on_success: bool,
+    /// Directory for av1an temp directories instead of beside the sources, e.g. on a faster disk than the library
+    #[arg(long, default_value = None)]
+    pub temp_dir: Option<PathBuf>,
+    /// GB that have to stay free on the temp and output volumes on top of what an episode is estimated to need, checked before each episode
+    #[arg(long, default_value = None)]
+    pub min_disk_space: Option<f64>,
+    /// Also keep the .ffprobe, .offset and .ssimu2 caches in --temp-dir
+    #[arg(long, num_args = 0, requires = "temp_dir", default_value_t = false)]
+    pub temp_caches: bool,
+    /// Track finished sources in .grav1an-state.json in the output directory, unchanged sources are skipped even if their outputs were moved and replaced ones are encoded again
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub incremental: bool,
+    /// Check what an interrupted batch left of each episode before continuing it, removing broken intermediates and outputs, and print where each episode picks up
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub resume_batch: bool,
+    /// Write an .nfo with the encode settings and tracks next to the output
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub nfo: bool,
+    /// Append the CRC32 of the output to its file name, like "[Group] Show - 01 [1080p.AV1] [ABCD1234].mkv"
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub crc_in_name: bool,
+    /// Write the SHA-256 of the output to a .sha256 file next to it
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub sha256: bool,
+    /// Split the output with mkvmerge's --split, like duration:00:10:00 or size:700M. The pieces go into a folder named after the output, which is what the torrent is made of
+    #[arg(long, conflicts_with_all = ["crc_in_name", "sha256", "nfo", "incremental", "resume_batch"], default_value = None)]
+    pub split: Option<String>,
     /// Skip creating a torrent file
     #[arg(long, num_args = 0, default_value_t = false)]
     pub no_torrent: bool,
-    /// Url for source file
+    /// Last line of the torrent comment
+    #[arg(long, default_value_t = String::from("Interested in AV1?: https://discord.gg/83dRFDFDp7"))]
+    pub footer: String,
+    /// Leave the footer out of the torrent comment
+    #[arg(long, num_args = 0, conflicts_with = "footer", default_value_t = false)]
+    pub no_footer: bool,
+    /// "created by" of the torrent, defaults to --group
+    #[arg(long, default_value = None)]
+    pub torrent_creator: Option<String>,
+    /// Unix timestamp to use as the torrent creation date instead of now, for byte identical torrents when recreating
+    #[arg(long, default_value = None)]
+    pub torrent_date: Option<i64>,
+    /// Extra file to put in the torrent next to the output, like an nfo or a sample, repeatable
+    #[arg(long, default_value = None)]
+    pub torrent_include: Vec<PathBuf>,
+    /// Hash the files again after writing the torrent and fail if they no longer match it
+    #[arg(long, num_args = 0, default_value_t = false)]
+    pub verify_torrent: bool,
+    /// Set the torrent creation date to 0
+    #[arg(long, num_args = 0, conflicts_with = "torrent_date", default_value_t = false)]
+    pub reproducible: bool,
+    /// Url for source file, set to the input itself for URL inputs
     #[arg(long, default_value = None)]
     pub source_url: Option<String>,
     /// Url for series info
     #[arg(long, default_value = None)]
     pub source_info: Option<String>,
+    /// AniList ID to fetch the series title from, requires the metadata feature
+    #[arg(long, conflicts_with = "tvdb_id", default_value = None)]
+    pub anilist_id: Option<u32>,
+    /// TVDB ID to fetch the series title from, requires the metadata feature
+    #[arg(long, requires = "tvdb_api_key", default_value = None)]
+    pub tvdb_id: Option<u32>,
+    /// TVDB API key used with --tvdb-id
+    #[arg(long, default_value = None)]
+    pub tvdb_api_key: Option<String>,
+    /// Series title fetched with --anilist-id or --tvdb-id
+    #[arg(skip)]
+    pub series_title: Option<String>,
     /// Single batch torrent
     #[arg(short, long, num_args = 0, default_value_t = false)]
     pub batch: bool,
-}
\ No newline at end of file
+}
+impl Args {
+    /// Q/crf range allowed for the final pass as [min, max], --q-min/--q-max take precedence over --quantizer-range
+    pub fn quantizer_range(&self) -> [f32; 2] {
+        let default = if self.encoder == "rav1e" {
+            [40.0, 160.0]
+        } else {
+            [25.0, 55.0]
+        };
+        let range = self.quantizer_range.unwrap_or(default);
+        [self.q_min.unwrap_or(range[0]), self.q_max.unwrap_or(range[1])]
+    }
+
+    /// Provenance used for the video track name, tags, nfo and torrent comment, like "WEB-DL AMZN"
+    pub fn source_label(&self) -> String {
+        let source_type = self.source_type.clone().unwrap_or(self.raws.clone());
+        match &self.source_platform {
+            Some(platform) => format!("{source_type} {platform}"),
+            None => source_type,
+        }
+    }
+
+    /// Bracketed part of the output name, the source label only goes in it when --source-type or --source-platform is given
+    pub fn name_suffix(&self) -> String {
+        if self.source_type.is_some() || self.source_platform.is_some() {
+            format!("{} {}", self.source_label(), self.suffix)
+        } else {
+            self.suffix.clone()
+        }
+    }
+
+    /// --input-file is a printf style pattern of numbered images
+    pub fn image_sequence(&self) -> bool {
+        let Some(name) = self.input_file.as_ref().and_then(|f| f.file_name()) else {
+            return false;
+        };
+        name.to_string_lossy().split('%').skip(1).any(|spec| spec.trim_start_matches(|c: char| c.is_ascii_digit()).starts_with('d'))
+    }
+
+    /// Display name of --target-metric for tags and torrent comments
+    pub fn target_metric_name(&self) -> &'static str {
+        if self.target_metric == "mean" {
+            "Mean"
+        } else if self.target_metric == "median" {
+            "Median"
+        } else if self.target_metric == "p5" {
+            "5th percentile"
+        } else {
+            "16th percentile"
+        }
+    }
+
+    /// Whether the muxed file goes to stdout instead of the output directory
+    pub fn to_stdout(&self) -> bool {
+        self.output_directory == PathBuf::from("-")
+    }
+
+    /// Series name used in output file names, the fetched title wins over --name
+    pub fn series_name(&self) -> String {
+        let name = self.series_title.as_ref().unwrap_or(&self.name);
+        name.replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "")
+    }
+
+    /// --output-template with the series name, season and group filled in
+    fn expanded_output_template(&self) -> Option<String> {
+        let template = self.output_template.as_ref()?;
+        Some(template.replace("{name}", &self.series_name()).replace("{season}", &self.season).replace("{group}", &self.group))
+    }
+
+    /// Directory the episode is written to, --output-template expanded below --output-directory
+    pub fn episode_output_directory(&self) -> PathBuf {
+        match self.expanded_output_template() {
+            Some(expanded) => self.output_directory.join(expanded),
+            None => self.output_directory.clone(),
+        }
+    }
+
+    /// Merges `<source stem>.grav1an.toml` over the command line for a single episode.
+    /// Keys are long option names (`denoise = 0.2`, `no-denoise = true`), sidecar values take precedence over the command line
+    pub fn with_sidecar(&self, file: &PathBuf) -> Args {
+        let sidecar = file.with_extension("grav1an.toml");
+        if sidecar.try_exists().is_ok_and(|b| b == false) {
+            return self.clone();
+        }
+        println_log!("Applying episode overrides from {}", sidecar.display());
+        let contents = std::fs::read_to_string(&sidecar).expect("Failed to read episode overrides!");
+        let table: toml::Table = contents.parse().unwrap_or_else(|e| panic!("Failed to parse {}: {e}", sidecar.display()));
+        let sets_name = table.contains_key("name");
+        let mut overrides: Vec<OsString> = Vec::new();
+        for (key, value) in table {
+            let flag = format!("--{key}");
+            if let toml::Value::Boolean(enabled) = value {
+                if enabled {
+                    overrides.push(flag.into());
+                } else {
+                    eprintln_log!("WARNING: {key} = false in {} has no effect, flags can only be enabled", sidecar.display());
+                }
+            } else if let toml::Value::String(string) = value {
+                overrides.append(&mut vec![flag.into(), string.into()]);
+            } else {
+                overrides.append(&mut vec![flag.into(), value.to_string().into()]);
+            }
+        }
+        let mut args = Args::try_parse_from(std::env::args_os().chain(overrides)).unwrap_or_else(|e| e.exit());
+        // what main derived from the command line, parsing it again resets these
+        args.input_directory = self.input_directory.clone();
+        args.input_file = self.input_file.clone();
+        args.source_url = self.source_url.clone();
+        args.no_torrent |= self.no_torrent;
+        if args.src2_directory.is_none() {
+            args.src2_directory = self.src2_directory.clone();
+        }
+        if !sets_name {
+            args.series_title = self.series_title.clone();
+        }
+        args.validate();
+        args
+    }
+
+    /// Av1an's --photon-noise strength for --grain-backend native, which is the ISO value divided by 100
+    pub fn native_photon_noise(&self) -> Option<u16> {
+        ((self.grain_backend == "native" || self.adaptive_grain.is_some()) && !self.no_grain).then(|| (self.photon_noise as f32 / 100.).round() as u16)
+    }
+
+    /// --av1an-args split into separate arguments
+    pub fn av1an_args(&self) -> Vec<String> {
+        self.av1an_args.as_deref().and_then(shlex::split).unwrap_or_default()
+    }
+
+    /// --grain-params split into separate arguments
+    pub fn grain_params(&self) -> Vec<String> {
+        self.grain_params.as_deref().and_then(shlex::split).unwrap_or_default()
+    }
+
+    /// Av1an chunk method, the source filter unless set explicitly
+    pub fn chunk_method(&self) -> &str {
+        self.chunk_method.as_deref().unwrap_or(self.source_filter.as_str())
+    }
+
+    /// Pixel format given to av1an, follows --output-depth and --match-chroma unless set explicitly
+    pub fn pixel_format(&self) -> String {
+        if let Some(pixel_format) = &self.pixel_format {
+            return pixel_format.clone();
+        }
+        let chroma = self.output_chroma.as_deref().unwrap_or("420");
+        if self.output_depth == "8" {
+            format!("yuv{chroma}p")
+        } else {
+            format!("yuv{chroma}p{}le", self.output_depth)
+        }
+    }
+
+    /// Exits with an error if the arguments can't produce the requested encode
+    pub fn validate(&self) {
+        if self.grain_params.is_some() && self.native_photon_noise().is_some() {
+            Args::command()
+                .error(ErrorKind::ArgumentConflict, "--grain-params is passed to grav1synth, which --grain-backend native doesn't run")
+                .exit();
+        }
+        if self.image_sequence() && self.fps_override.is_none() {
+            Args::command()
+                .error(ErrorKind::MissingRequiredArgument, "an image sequence has no frame rate, give it with --fps-override")
+                .exit();
+        }
+        if self.encoder == "rav1e" && self.probe_speed > 10 {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--probe-speed {} is out of rav1e's 0-10 range", self.probe_speed))
+                .exit();
+        }
+        // the quality curve is a cubic fit over the probes
+        if self.probe_points.iter().unique().count() < 4 {
+            Args::command()
+                .error(ErrorKind::ValueValidation, "--probe-points needs at least 4 different points")
+                .exit();
+        }
+        if self.native_photon_noise().is_some_and(|strength| strength == 0 || strength > 64) {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--photon-noise {} can't be applied natively, av1an takes ISO 100 to 6400", self.photon_noise))
+                .exit();
+        }
+        // clap ignores a requires on --input-file while the conflicting --input-directory is given
+        if self.src2_file.is_some() && self.input_file.is_none() {
+            Args::command()
+                .error(ErrorKind::MissingRequiredArgument, "--src2-file can only be used with --input-file")
+                .exit();
+        }
+        if let Some(missing) = self.torrent_include.iter().find(|p| !p.is_file()) {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--torrent-include {} is not a file", missing.display()))
+                .exit();
+        }
+        if self.review && !std::io::stdin().is_tty() {
+            Args::command()
+                .error(ErrorKind::ArgumentConflict, "--review needs an interactive terminal to answer its prompts")
+                .exit();
+        }
+        if self.scenes.as_ref().is_some_and(|scenes| scenes.try_exists().is_ok_and(|b| b == false)) {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("scenes file {} does not exist", self.scenes.as_ref().unwrap().display()))
+                .exit();
+        }
+        if self.match_chroma && self.encoder == "svt-av1" {
+            Args::command()
+                .error(ErrorKind::ArgumentConflict, "svt-av1 only encodes 4:2:0, use --encoder rav1e for --match-chroma")
+                .exit();
+        }
+        if self.output_depth == "12" && self.encoder == "svt-av1" {
+            Args::command()
+                .error(ErrorKind::ValueValidation, "svt-av1 can't encode 12-bit video, use --encoder rav1e or a lower --output-depth")
+                .exit();
+        }
+        if self.to_stdout() {
+            let incompatible = [("--batch", self.batch), ("--incremental", self.incremental), ("--nfo", self.nfo), ("--crc-in-name", self.crc_in_name), ("--sha256", self.sha256), ("--sample-scenes", self.sample_scenes.is_some()), ("--share-probes", self.share_probes), ("--output-template", self.output_template.is_some()), ("--split", self.split.is_some())];
+            if let Some((flag, _)) = incompatible.iter().find(|(_, set)| *set) {
+                Args::command()
+                    .error(ErrorKind::ArgumentConflict, format!("{flag} needs a real output directory and can't be used with -o -"))
+                    .exit();
+            }
+            // a URL given with -i is downloaded into a single --input-file
+            let url = self.input_directory.to_str().is_some_and(|p| p.starts_with("http://") || p.starts_with("https://"));
+            if self.input_file.is_none() && !url {
+                Args::command()
+                    .error(ErrorKind::ArgumentConflict, "-o - needs exactly one source, use --input-file")
+                    .exit();
+            }
+            if !cfg!(unix) {
+                Args::command()
+                    .error(ErrorKind::ValueValidation, "-o - is only supported on unix")
+                    .exit();
+            }
+        }
+        if let Some(expanded) = self.expanded_output_template() {
+            if !PathBuf::from(&expanded).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+                Args::command()
+                    .error(ErrorKind::ValueValidation, format!("--output-template {} expands to {expanded}, which isn't inside the output directory", self.output_template.as_ref().unwrap()))
+                    .exit();
+            }
+        }
+        let [min, max] = self.quantizer_range();
+        if min >= max {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("quantizer range [{min}, {max}] is empty or inverted, --q-min must be lower than --q-max"))
+                .exit();
+        }
+        if self.single_pass {
+            return;
+        }
+        if self.quantizer < min || self.quantizer > max {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--quantizer {} is outside of the quantizer range [{min}, {max}]", self.quantizer))
+                .exit();
+        }
+        if self.target_quality <= 0.0 || self.target_quality > 100.0 {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--target-quality {} is not a valid SSIMULACRA 2 score, expected a value above 0 and up to 100", self.target_quality))
+                .exit();
+        }
+    }
+}
+
+fn parse_quantizer_range(range: &str) -> Result<[f32; 2], String> {
+    serde_json::from_str::<[f32; 2]>(range).map_err(|_| format!("expected a range like [25,55], got {range}"))
+}
+
+fn parse_grain_thresholds(thresholds: &str) -> Result<[f64; 2], String> {
+    let parsed = thresholds.split_once(",").and_then(|(low, high)| Some([low.trim().parse::<f64>().ok()?, high.trim().parse::<f64>().ok()?]));
+    match parsed {
+        Some([low, high]) if low >= 0. && low < high => Ok([low, high]),
+        _ => Err(format!("expected two increasing grain levels like 0.5,2, got {thresholds}")),
+    }
+}
+
+fn parse_shell_words(arguments: &str) -> Result<String, String> {
+    match shlex::split(arguments) {
+        Some(_) => Ok(arguments.to_string()),
+        None => Err(format!("unbalanced quotes or a trailing backslash in {arguments}")),
+    }
+}
+
+fn parse_frame_rate(rate: &str) -> Result<String, String> {
+    let valid = rate.split_once("/").is_some_and(|(numerator, denominator)| {
+        numerator.parse::<u32>().is_ok_and(|n| n > 0) && denominator.parse::<u32>().is_ok_and(|d| d > 0)
+    });
+    if valid {
+        Ok(rate.to_string())
+    } else {
+        Err(format!("expected a frame rate like 24000/1001, got {rate}"))
+    }
+}
+
+// stored as ISO 639-3 to compare with Probe::language
+fn parse_language(code: &str) -> Result<String, String> {
+    let language = if code.len() == 2 {
+        Language::from_639_1(code)
+    } else {
+        Language::from_639_3(code)
+    };
+    language.map(|l| l.to_639_3().to_string()).ok_or(format!("expected an ISO 639 language code like eng or en, got {code}"))
+}
+
+fn parse_workers(workers: &str) -> Result<String, String> {
+    if workers == "auto" || workers.parse::<u8>().is_ok_and(|w| w > 0) {
+        Ok(workers.to_string())
+    } else {
+        Err(format!("expected a worker count or auto, got {workers}"))
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The tools get Ctrl-C from the terminal too, av1an finishes its current chunks and keeps its resume data.
+// The first one only keeps grav1an from starting the next step, the second one exits right away.
+pub fn init() {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln_log!("\nAborted. Exiting script.");
+            exit(130);
+        }
+        eprintln_log!("\nInterrupted, stopping after the current step. Press Ctrl-C again to exit now.");
+    })
+    .expect("Failed to set the Ctrl-C handler!");
+}
+
+// between steps, nothing new is started once interrupted
+pub fn check() {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        println_log!("Stopped. Run the same command again to resume.");
+        exit(130);
+    }
+}
+
+// what an interrupted step was writing is incomplete, resuming would trust it just because it exists
+pub fn discard(outputs: &[&PathBuf]) {
+    if !INTERRUPTED.load(Ordering::SeqCst) {
+        return;
+    }
+    for output in outputs {
+        if output.try_exists().is_ok_and(|b| b == true) {
+            if let Err(e) = fs::remove_file(output) {
+                eprintln_log!("WARNING: Failed to remove incomplete {}: {e}", output.display());
+            }
+        }
+    }
+    check();
+}
@@ -0,0 +1,64 @@
+use chrono::Local;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use itertools::Itertools;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+// appended to, so resuming a batch keeps the log of the earlier runs
+pub fn init(path: &PathBuf) {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap_or_else(|e| panic!("Failed to open log file {}: {e}", path.display()));
+    let _ = LOG_FILE.set(Mutex::new(file));
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        write("PANIC", info.to_string().as_str());
+        default_hook(info);
+    }));
+}
+
+pub fn write(level: &str, message: &str) {
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+    let mut file = file.lock().unwrap();
+    for line in message.lines().filter(|l| !l.trim().is_empty()) {
+        let _ = writeln!(file, "{timestamp} {level:<5} {line}");
+    }
+}
+
+// every visible progress bar is drawn through one MultiProgress, so printed lines go above all of them
+fn progress() -> &'static MultiProgress {
+    PROGRESS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    progress().suspend(f)
+}
+
+// the batch bar is added first and stays at the bottom, the bars of an episode go above it and are printed for good once they're dropped
+pub fn add_bar(bar: ProgressBar) -> ProgressBar {
+    progress().insert_from_back(1, bar)
+}
+
+// logs the command line of every external tool right before it's started
+pub trait Logged {
+    fn logged(&mut self) -> &mut Self;
+}
+
+impl Logged for Command {
+    fn logged(&mut self) -> &mut Self {
+        let arguments = self.get_args().map(|a| a.to_string_lossy()).join(" ");
+        write("CMD", format!("{} {arguments}", self.get_program().to_string_lossy()).as_str());
+        self
+    }
+}
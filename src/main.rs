@@ -1,31 +1,97 @@
 use core::str;
 use clap::Parser;
 use fancy_regex::Regex;
-use futures::io::AllowStdIo as asyncio;
 use isolang::Language;
 use itertools::Itertools;
 use phf::phf_map;
 use polyfit_rs::polyfit_rs::polyfit;
+use rustfft::{num_complex::Complex, FftPlanner};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use statrs::statistics::{Distribution, Median, OrderStatistics};
 use std::ffi::{OsStr, OsString};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, Read, Write};
 use std::process::{exit, Command, Stdio};
-use std::{fmt::Debug, fs::File, path::absolute as abs, path::PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::thread::available_parallelism;
+use std::time::Duration;
+use std::{env, fmt::Debug, fs, fs::File, path::absolute as abs, path::Path, path::PathBuf};
 use which::which;
+
+// println!/eprintln! that also go to --log-file, progress bars only ever go to the terminal and are hidden while a line is printed
+macro_rules! println_log {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $crate::logging::write("INFO", &message);
+        $crate::logging::suspend(|| println!("{message}"));
+    }};
+}
+
+macro_rules! eprintln_log {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $crate::logging::write(if message.starts_with("WARNING") { "WARN" } else { "ERROR" }, &message);
+        $crate::logging::suspend(|| eprintln!("{message}"));
+    }};
+}
+
+mod logging;
+mod interrupt;
 mod ssimulacra2;
 mod args;
 mod torrent;
+mod metadata;
 use self::args::Args;
 use self::torrent::create_torrent;
 use self::ssimulacra2::*;
+use self::logging::Logged;
 
 // mixing &str and String is painful
 macro_rules! vec_into {
     ($($x:expr),*) => (vec![$($x.into()),*]);
 }
 
+// --incremental, source path -> what it produced last time
+#[derive(Serialize, Deserialize, Default)]
+struct BatchState {
+    episodes: BTreeMap<String, EpisodeState>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct EpisodeState {
+    fingerprint: String,
+    output: PathBuf,
+}
+
+// --share-probes, Q/crf as a function of the episode's target metric for similar episodes to reuse
+#[derive(Serialize, Deserialize, Default)]
+struct SharedProbes {
+    episodes: Vec<SharedFit>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SharedFit {
+    source: String,
+    group: Option<String>,
+    width: Option<u16>,
+    height: Option<u16>,
+    duration: f64,
+    coefficients: Vec<f64>,
+}
+
+impl SharedFit {
+    // same release group and resolution and no more than 10% apart in length
+    fn similar(&self, other: &SharedFit) -> bool {
+        self.group == other.group
+            && self.width == other.width
+            && self.height == other.height
+            && (self.duration - other.duration).abs() <= self.duration.max(other.duration) * 0.1
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct ScenesInfo {
     scenes: Vec<Scene>,
@@ -51,25 +117,60 @@ struct QuantizerScores {
     percentile_95th: f64,
 }
 
+impl QuantizerScores {
+    fn get(&self, metric: &str) -> f64 {
+        if metric == "mean" {
+            self.mean
+        } else if metric == "median" {
+            self.median
+        } else if metric == "p5" {
+            self.percentile_5th
+        } else {
+            self.percentile_16th
+        }
+    }
+}
+
+// defaults and aliases let hand-written or other av1an versions' scene files deserialize
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct ZoneOverrides {
     encoder: String,
+    #[serde(default = "default_passes")]
     passes: u8,
+    #[serde(default)]
     video_params: Vec<String>,
     photon_noise: Option<u16>,
+    #[serde(default)]
+    chroma_noise: bool,
+    #[serde(default = "default_extra_split_sec", alias = "extra_splits_len")]
     extra_split_sec: u8,
+    #[serde(default = "default_min_scene_len")]
     min_scene_len: u8,
 }
 
+fn default_passes() -> u8 {
+    1
+}
+
+fn default_extra_split_sec() -> u8 {
+    10
+}
+
+fn default_min_scene_len() -> u8 {
+    24
+}
+
 #[derive(Deserialize, Clone, Debug)]
 struct Stream {
-    index: u8,
+    index: u32,
     #[serde(default)]
     codec_name: String,
     #[serde(default)]
     codec_type: String,
     avg_frame_rate: Option<String>,
-    start_pts: u32,
+    #[serde(default)]
+    start_pts: i64,
+    time_base: Option<String>,
     channels: Option<u8>,
     width: Option<u16>,
     height: Option<u16>,
@@ -79,6 +180,8 @@ struct Stream {
     color_range: Option<String>,
     color_transfer: Option<String>,
     color_primaries: Option<String>,
+    duration: Option<String>,
+    bit_rate: Option<String>,
     disposition: Disposition,
     tags: Tags,
 }
@@ -94,6 +197,8 @@ struct Tags {
     bps: Option<String>,
     #[serde(rename = "ENCODER_OPTIONS")]
     encoder_options: Option<String>,
+    #[serde(rename = "DURATION")]
+    duration: Option<String>,
     language: Option<String>,
     title: Option<String>,
 }
@@ -115,7 +220,8 @@ struct Probe {
     stream: Stream,
     file: PathBuf,
     offset: i32,
-    index: Option<u8>,
+    index: Option<u32>,
+    src2: bool,
 }
 impl Probe {
     fn language(&self) -> Language {
@@ -131,9 +237,21 @@ impl Probe {
             return Language::from_639_1(code).unwrap_or(Language::Und);
         };
     }
+    // matroska's BPS tag, or what the container header says for the stream
     fn bit_rate(&self) -> u32 {
-        let bps = self.stream.tags.bps.clone();
-        return bps.or(Some(0.to_string())).unwrap().parse().unwrap();
+        let bps = self.stream.tags.bps.clone().or(self.stream.bit_rate.clone());
+        return bps.and_then(|b| b.parse().ok()).unwrap_or(0);
+    }
+    // seconds, matroska only stores it in the DURATION tag as HH:MM:SS.nnnnnnnnn
+    fn duration(&self) -> f64 {
+        if let Some(duration) = self.stream.duration.as_ref().and_then(|d| d.parse::<f64>().ok()) {
+            return duration;
+        }
+        let tag = self.stream.tags.duration.clone().unwrap_or(String::new());
+        tag.split(":").fold(0.0, |acc, part| acc * 60.0 + part.parse::<f64>().unwrap_or(0.0))
+    }
+    fn resolution(&self) -> u32 {
+        self.stream.width.unwrap_or(0) as u32 * self.stream.height.unwrap_or(0) as u32
     }
     fn pix_fmt(&self, vs: bool) -> String {
         let pix_fmt = &self.stream.pix_fmt;
@@ -173,6 +291,13 @@ impl Probe {
         let (numerator, denominator) = stream.avg_frame_rate.as_ref().unwrap().split("/").collect_tuple().unwrap();
         numerator.parse::<f64>().unwrap() / denominator.parse::<f64>().unwrap()
     }
+    // start_pts counts in the stream's time base, e.g. 1/48000 for audio muxed by ffmpeg
+    fn start_ms(&self) -> i32 {
+        let stream = &self.stream;
+        let time_base = stream.time_base.as_deref().unwrap_or("1/1000");
+        let (numerator, denominator) = time_base.split("/").map(|n| n.parse::<f64>().unwrap()).collect_tuple().unwrap();
+        (stream.start_pts as f64 * numerator * 1000. / denominator).round() as i32
+    }
     fn color_data(&self, rav1e: bool) -> (String, String, String, String) {
         let stream = self.stream.clone();
         let range = stream.color_range.unwrap_or("tv".to_string());
@@ -251,26 +376,290 @@ impl Probe {
     }
 }
 
+// the original stdout while -o - points it at stderr, the muxed file is copied into it at the end
+static STDOUT_PIPE: OnceLock<File> = OnceLock::new();
+
+// with -o - the muxed file is the only thing on stdout, grav1an and the tools it runs print to stderr instead
+#[cfg(unix)]
+fn redirect_stdout() {
+    use std::os::fd::FromRawFd;
+    let pipe = unsafe { libc::dup(1) };
+    if pipe < 0 || unsafe { libc::dup2(2, 1) } < 0 {
+        panic!("Failed to redirect stdout: {}", io::Error::last_os_error());
+    }
+    let _ = STDOUT_PIPE.set(unsafe { File::from_raw_fd(pipe) });
+}
+
+#[cfg(not(unix))]
+fn redirect_stdout() {
+    unreachable!("-o - is rejected outside of unix");
+}
+
+static STDIN_LINES: OnceLock<Mutex<Receiver<String>>> = OnceLock::new();
+
+// stdin is read on its own thread so a prompt can stop waiting, answers typed while nothing was asking are dropped
+fn read_answer(timeout: Option<u64>) -> Result<String, RecvTimeoutError> {
+    let lines = STDIN_LINES.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || loop {
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input).unwrap_or(0) == 0 || tx.send(input).is_err() {
+                break;
+            }
+        });
+        Mutex::new(rx)
+    });
+    let lines = lines.lock().unwrap();
+    while lines.try_recv().is_ok() {}
+    if let Some(secs) = timeout {
+        lines.recv_timeout(Duration::from_secs(secs))
+    } else {
+        lines.recv().map_err(|_| RecvTimeoutError::Disconnected)
+    }
+}
+
+// yes/no pause, only "yes" in any case goes on and no answer within the timeout counts as a yes
+fn confirm(question: &str, timeout: Option<u64>) -> bool {
+    println_log!("PAUSED: {question}");
+    print!("(yes/no): ");
+    io::stdout().flush().expect("Failed to flush!");
+    let answer = read_answer(timeout);
+    if let Err(RecvTimeoutError::Timeout) = answer {
+        println_log!("\nNo answer after {}s.", timeout.unwrap());
+        return true;
+    }
+    answer.is_ok_and(|input| input.trim().eq_ignore_ascii_case("yes"))
+}
+
+// --*-bin overrides, filled once in main before anything is spawned
+static BINARIES: OnceLock<HashMap<&'static str, PathBuf>> = OnceLock::new();
+
+fn set_binaries(args: &Args) {
+    #[rustfmt::skip]
+    let overrides = [("SvtAv1EncApp", &args.svt_bin), ("rav1e", &args.rav1e_bin), ("ffmpeg", &args.ffmpeg_bin), ("av1an", &args.av1an_bin), ("mkvmerge", &args.mkvmerge_bin), ("grav1synth", &args.grav1synth_bin)];
+    let mut binaries = HashMap::new();
+    for (name, path) in overrides {
+        if let Some(path) = path {
+            let binary = which(path).expect(format!("{} isn't an executable file!", path.display()).as_str());
+            binaries.insert(name, binary);
+        }
+    }
+    // av1an looks up the encoders itself, so the override directories go first in PATH
+    if !binaries.is_empty() {
+        let dirs = binaries.values().map(|b| b.parent().unwrap().to_path_buf()).unique();
+        let path = env::var_os("PATH").unwrap_or_default();
+        env::set_var("PATH", env::join_paths(dirs.chain(env::split_paths(&path))).unwrap());
+    }
+    BINARIES.set(binaries).unwrap();
+}
+
 fn get_binary(path: &str) -> PathBuf {
+    if let Some(binary) = BINARIES.get().and_then(|b| b.get(path)) {
+        return binary.clone();
+    }
     return which(path).expect(format!("Couldn't find {path} in PATH").as_str());
 }
 
+// --temp-dir and whether the caches go there too, filled once in main
+static TEMP_DIR: OnceLock<(PathBuf, bool)> = OnceLock::new();
+
+fn set_temp_dir(args: &Args) {
+    if let Some(dir) = &args.temp_dir {
+        fs::create_dir_all(dir).expect(format!("Failed to create temp directory {}", dir.display()).as_str());
+        TEMP_DIR.set((abs(dir).unwrap(), args.temp_caches)).unwrap();
+    }
+}
+
+// a path that would sit beside the source, moved under --temp-dir and prefixed with a hash of the source's directory so same named episodes of different shows don't collide
+fn relocate(path: PathBuf, dir: &Path) -> PathBuf {
+    let parent = abs(path.parent().unwrap()).unwrap();
+    dir.join(format!("{:08x}_{}", crc32fast::hash(parent.as_os_str().as_encoded_bytes()), path_str(path.file_name().unwrap())))
+}
+
+fn av1an_temp(path: PathBuf) -> PathBuf {
+    match TEMP_DIR.get() {
+        Some((dir, _)) => relocate(path, dir),
+        None => path,
+    }
+}
+
+fn cache_path(path: PathBuf) -> PathBuf {
+    match TEMP_DIR.get() {
+        Some((dir, true)) => relocate(path, dir),
+        _ => path,
+    }
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(abs(path).ok()?.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+// av1an's chunks, the probes, the joined remux and the grain copies take up to about twice the source on the temp volume,
+// the output stays below the size of the source
+fn check_disk_space(file_path: &PathBuf, args: &Args) {
+    let Some(min_disk_space) = args.min_disk_space else {
+        return;
+    };
+    let source_size = file_path.metadata().unwrap().len();
+    let reserve = (min_disk_space * 1e9) as u64;
+    let temp_dir = TEMP_DIR.get().map(|(dir, _)| dir.clone()).unwrap_or(file_path.parent().unwrap().to_path_buf());
+    let mut volumes = vec![(temp_dir, source_size * 2)];
+    if !args.to_stdout() {
+        volumes.push((args.output_directory.clone(), source_size));
+    }
+    for (dir, needed) in volumes {
+        let Some(free) = free_space(&dir) else {
+            eprintln_log!("WARNING: Couldn't get the free space of {}, --min-disk-space isn't checked", dir.display());
+            continue;
+        };
+        if free < needed + reserve {
+            panic!(
+                "Only {:.1} GB free on the volume of {}, {} needs about {:.1} GB plus the {min_disk_space} GB of --min-disk-space. Free up space or point --temp-dir elsewhere.",
+                free as f64 / 1e9,
+                dir.display(),
+                file_path.display(),
+                needed as f64 / 1e9
+            );
+        }
+    }
+}
+
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(log_file) = &args.log_file {
+        logging::init(log_file);
+    }
+    interrupt::init();
+    if args.quantizer_range.is_some() {
+        eprintln_log!("WARNING: --quantizer-range is deprecated, use --q-min and --q-max instead");
+    }
+    // a single file works like a directory holding only that file, caches and sidecars go next to it
+    if let Some(file) = &args.input_file {
+        args.input_directory = abs(file).unwrap().parent().unwrap().to_path_buf();
+    }
+    if let Some(file) = &args.src2_file {
+        args.src2_directory = Some(abs(file).unwrap().parent().unwrap().to_path_buf());
+    }
+    args.validate();
+    check_av1an_args(&args);
+    if args.to_stdout() {
+        redirect_stdout();
+        args.no_torrent = true;
+    }
+    set_binaries(&args);
+    set_temp_dir(&args);
+    set_progress_style(&args);
+    // a URL input is downloaded once and then handled like --input-file
+    let download = input_url(&args).map(|url| {
+        let file = download_source(&url);
+        args.input_directory = file.parent().unwrap().to_path_buf();
+        args.input_file = Some(file.clone());
+        args.source_url.get_or_insert(url);
+        file
+    });
+    if args.image_sequence() {
+        args.input_file = Some(sequence_script(args.input_file.as_ref().unwrap(), &args));
+    }
+    metadata::apply(&mut args);
+    let cleanup = args.cleanup_on_success;
     process_command(args);
+    if let Some(download) = download.filter(|_| cleanup) {
+        if let Err(e) = fs::remove_file(&download) {
+            eprintln_log!("WARNING: Failed to remove download {}: {e}", download.display());
+        }
+    }
+}
+
+// av1an rejects most options given twice, so the ones grav1an already sets are pointed out before anything runs
+fn check_av1an_args(args: &Args) {
+    #[rustfmt::skip]
+    let managed = ["-i", "--input", "-o", "--output-file", "--temp", "--verbose", "--resume", "-w", "--workers", "--scenes", "--sc-only", "--sc-pix-format", "--sc-downscale-height", "-e", "--encoder", "-v", "--video-params", "-m", "--chunk-method", "-c", "--concat", "--pix-format", "--keep", "--photon-noise"];
+    for argument in args.av1an_args() {
+        let flag = argument.split("=").next().unwrap();
+        if managed.contains(&flag) {
+            eprintln_log!("WARNING: --av1an-args sets {flag}, which grav1an already passes to av1an, av1an may reject it");
+        }
+    }
+}
+
+fn input_url(args: &Args) -> Option<String> {
+    let inputs = [args.input_file.as_ref(), Some(&args.input_directory)];
+    inputs.into_iter().flatten().filter_map(|p| p.to_str()).find(|p| p.starts_with("http://") || p.starts_with("https://")).map(String::from)
+}
+
+// "Show%20-%2001.mkv" -> "Show - 01.mkv", escapes that aren't valid are kept as they are
+fn percent_decode(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%').then(|| name.get(i + 1..i + 3)).flatten().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).replace(['/', '\\'], "_")
+}
+
+// remuxed by ffmpeg straight from the URL into --temp-dir or the system's temp directory, never the output directory a batch torrent is made of.
+// Every URL gets a directory named after its hash so different sites' "01.mkv" don't reuse each other, the file keeps the name for the episode number.
+// A finished download is reused, an interrupted one is started over.
+fn download_source(url: &String) -> PathBuf {
+    let name = url.split(['?', '#']).next().unwrap().rsplit("/").next().filter(|n| !n.is_empty()).unwrap_or("download");
+    let name = percent_decode(name);
+    let stem = Path::new(&name).file_stem().unwrap();
+    let downloads = match TEMP_DIR.get() {
+        Some((dir, _)) => dir.clone(),
+        None => env::temp_dir().join("grav1an-downloads"),
+    };
+    let dir = downloads.join(format!("{:08x}", crc32fast::hash(url.as_bytes())));
+    let file = dir.join(format!("{}.mkv", path_str(stem)));
+    if file.try_exists().is_ok_and(|b| b == true) {
+        println_log!("Reusing download {}", file.display());
+        return file;
+    }
+    fs::create_dir_all(&dir).unwrap();
+    let partial = file.with_extension("mkv.part");
+    println_log!("Downloading {url} to {}", file.display());
+    #[rustfmt::skip]
+    let status = Command::new(get_binary("ffmpeg"))
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i", url.as_str(), "-map", "0", "-c", "copy", "-f", "matroska", path_str(&partial).as_str()])
+        .logged().status().unwrap();
+    if !status.success() {
+        let _ = fs::remove_file(&partial);
+        panic!("Failed to download {url}!");
+    }
+    fs::rename(&partial, &file).unwrap();
+    file
 }
 
 #[rustfmt::skip]
 fn ffprobe(file: &PathBuf) -> FileProbe {
     let mut ffprobe: Vec<u8> = Vec::new();
-    let ffprobe_save = PathBuf::from(format!("{}.ffprobe", file.as_path().display()));
+    let ffprobe_save = cache_path(PathBuf::from(format!("{}.ffprobe", file.as_path().display())));
     if ffprobe_save.try_exists().is_ok_and(|b| b == true) {
-        println!("Reading cached ffprobe result at {}", ffprobe_save.display());
+        println_log!("Reading cached ffprobe result at {}", ffprobe_save.display());
         File::open(ffprobe_save).unwrap().read_to_end(&mut ffprobe).unwrap();
     } else {
         ffprobe = Command::new("ffprobe")
-            .args(["-v","error","-print_format","json","-show_streams","-hide_banner","-i",file.to_str().unwrap()])
-            .output()
+            .args(["-v","error","-print_format","json","-show_streams","-hide_banner","-i",path_str(&file).as_str()])
+            .logged().output()
             .unwrap().stdout;
         File::create(ffprobe_save).unwrap().write_all(&ffprobe).unwrap();
     }
@@ -279,14 +668,14 @@ fn ffprobe(file: &PathBuf) -> FileProbe {
 }
 
 fn match_episode(file_name: &OsString, episode_number: String, season: String) -> bool {
-    let temp_str = file_name.to_str().unwrap();
+    let temp_str = path_str(&file_name);
     let patterns = [
         Regex::new(format!("(?i)S{}E{}", season, episode_number).as_str()).unwrap(),
         Regex::new(format!("(?i)(?<!\\d)\\b{}\\b(?!\\d)", episode_number).as_str()).unwrap(),
     ];
     let mut regex_matched = false;
     for pattern in patterns {
-        let result = pattern.captures(temp_str);
+        let result = pattern.captures(&temp_str);
         if result.is_err() || result.unwrap().is_none() {
             continue;
         }
@@ -321,20 +710,153 @@ fn is_video(file: &PathBuf) -> bool {
         return false;
     }
     let tmp_str = file.extension().unwrap();
-    let video_extensions: Vec<&'static str> = vec!["mkv", "mp4", "webm", "avi", "mov", "ts", "m2t"];
+    let video_extensions: Vec<&'static str> = vec!["mkv", "mp4", "webm", "avi", "mov", "ts", "m2t", "y4m"];
+    // grav1an writes <name>.vpy next to every container source, only scripts without one are sources themselves
+    if is_script(file) {
+        return !video_extensions.iter().any(|extension| file.with_extension(extension).try_exists().is_ok_and(|b| b == true));
+    }
     return video_extensions.iter().any(|extension| tmp_str == *extension);
 }
 
+fn is_script(file: &PathBuf) -> bool {
+    file.extension().is_some_and(|e| e.eq_ignore_ascii_case("vpy"))
+}
+
+fn is_y4m(file: &PathBuf) -> bool {
+    file.extension().is_some_and(|e| e.eq_ignore_ascii_case("y4m"))
+}
+
+// an image sequence is wrapped in a script named after the pattern, from then on it's handled like any script source
+fn sequence_script(pattern: &PathBuf, args: &Args) -> PathBuf {
+    let name = path_str(&pattern.file_name().unwrap());
+    let captures = Regex::new(r"^(.*)%\d*d(.*)$").unwrap().captures(&name).unwrap().unwrap();
+    let (prefix, suffix) = (captures[1].to_string(), captures[2].to_string());
+    let dir = abs(pattern.parent().unwrap()).unwrap();
+    let numbers = dir.read_dir().unwrap().filter_map(|entry| {
+        let file = path_str(&entry.unwrap().file_name());
+        let number = file.strip_prefix(prefix.as_str())?.strip_suffix(suffix.as_str())?;
+        number.parse::<u32>().ok().filter(|_| number.chars().all(|c| c.is_ascii_digit()))
+    });
+    let first = numbers.min().unwrap_or_else(|| panic!("No images match {}", pattern.display()));
+    let (numerator, denominator) = args.fps_override.as_ref().unwrap().split_once("/").unwrap();
+    let stem = Some(prefix.trim_end_matches([' ', '_', '-', '.'])).filter(|s| !s.is_empty()).unwrap_or("sequence");
+    let script = cache_path(dir.join(format!("{stem}.vpy")));
+    // OpenEXR holds linear light, the other formats are already gamma encoded
+    let linear = suffix.to_lowercase().ends_with(".exr");
+    let transfer = if linear { ", transfer_in_s='linear', transfer_s='709'" } else { "" };
+    #[rustfmt::skip]
+    let content = format!(
+        "import vapoursynth as vs\n{}src = core.imwri.Read({}, firstnum={first})\nsrc = core.std.AssumeFPS(src, fpsnum={numerator}, fpsden={denominator})\nsrc = core.resize.Bicubic(src, format=vs.{}, matrix_s='709'{transfer})\nsrc.set_output(0)\n",
+        get_core_string(args, args.mem), py_path(&dir.join(&name)), vs_format(&args.pixel_format())
+    );
+    File::create(&script).unwrap().write_all(content.as_bytes()).unwrap();
+    println_log!("Image sequence {} starts at {first}, encoding it through {}", pattern.display(), script.display());
+    script
+}
+
+// the inverse of script_probe's naming, "yuv420p10le" -> "YUV420P10" and "yuv420p" -> "YUV420P8"
+fn vs_format(pix_fmt: &str) -> String {
+    let format = pix_fmt.trim_end_matches("le").to_uppercase();
+    if format.ends_with(|c: char| c.is_ascii_digit()) {
+        format
+    } else {
+        format!("{format}8")
+    }
+}
+
+// ffprobe can't open VapourSynth scripts, so the video stream is described from the script's output
+fn script_probe(file: &PathBuf) -> FileProbe {
+    let (width, height, frames, (numerator, denominator), format) = script_info(file);
+    let pix_fmt = if format.ends_with("P8") {
+        format.trim_end_matches('8').to_lowercase()
+    } else {
+        format!("{}le", format.to_lowercase())
+    };
+    #[rustfmt::skip]
+    let stream = serde_json::json!({
+        "index": 0, "codec_name": "vapoursynth", "codec_type": "video", "start_pts": 0,
+        "avg_frame_rate": format!("{numerator}/{denominator}"), "width": width, "height": height, "pix_fmt": pix_fmt,
+        "duration": (frames as f64 * denominator as f64 / numerator as f64).to_string(),
+        "disposition": { "forced": 0 }, "tags": {},
+    });
+    FileProbe { streams: vec![serde_json::from_value(stream).unwrap()] }
+}
+
+#[rustfmt::skip]
+const TEMP_EXTENSIONS: [&str; 26] = ["_enc.mkv","_grained.mkv","_lowest.mkv","_low.mkv","_high.mkv","_highest.mkv","_grainy.mkv","_cleaned.mkv","_clip.mkv", "_verify.mkv", "_stdout.mkv", "_joined.mkv", "_sample_enc.mkv", "_sample.vpy", "_estimate_enc.mkv", "_estimate.vpy", ".dgsource.vpy", "_scene_detect.vpy", "_skip.vpy", "_clean.vpy", "_merge.vpy", "_grain.vpy", ".ffprobe", ".offset", ".ssimu2", ".bps"];
+
 #[rustfmt::skip]
 fn is_temporary_file(file: &OsString) -> bool {
-    let tmp_str = file.to_str().unwrap();
-    let temp_extensions: Vec<&'static str> = vec!["_enc.mkv","_grained.mkv","_lowest.mkv","_low.mkv","_high.mkv","_highest.mkv","_grainy.mkv","_cleaned.mkv","_clip.mkv", ".ffprobe", ".offset", ".ssimu2"];
-    return temp_extensions.iter().any(|extension| tmp_str.ends_with(extension));
+    let tmp_str = path_str(&file);
+    let probe = Regex::new(r"_q[+-]\d+\.mkv$").unwrap();
+    return TEMP_EXTENSIONS.iter().any(|extension| tmp_str.ends_with(extension)) || probe.is_match(&tmp_str).unwrap_or(false);
+}
+
+// "Movie CD1" or "Show - 05.part2" -> the name without the part and the part number, titles like "Movie Part 1" are left alone
+fn split_part(file: &PathBuf) -> Option<(String, u32)> {
+    let stem = path_str(&file.file_stem()?);
+    let captures = Regex::new(r"(?i)^(.*?)(?:[ ._-]+cd|\.part)(\d{1,2})$").unwrap().captures(&stem).ok()??;
+    Some((captures[1].to_string(), captures[2].parse().ok()?))
+}
+
+// appending parts with different tracks would shift every track after the first part
+fn check_part_layouts(parts: &Vec<(u32, PathBuf)>) {
+    let layout = |file: &PathBuf| ffprobe(file).streams.iter().map(|s| (s.codec_type.clone(), s.codec_name.clone(), s.width, s.height, s.channels)).collect::<Vec<_>>();
+    let first = layout(&parts[0].1);
+    for (_, part) in &parts[1..] {
+        if layout(part) != first {
+            panic!("{} doesn't have the same tracks as {}, join the parts manually", part.display(), parts[0].1.display());
+        }
+    }
+}
+
+// keeps the video sources, ones split into CD1/CD2 or .part1/.part2 are appended into one _joined.mkv that replaces the parts
+fn join_parts(sources: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut groups: BTreeMap<(PathBuf, String), Vec<(u32, PathBuf)>> = BTreeMap::new();
+    let mut joined_sources = Vec::new();
+    for source in sources {
+        if !is_video(&source) || is_temporary_file(&source.file_name().unwrap().to_os_string()) {
+            continue;
+        }
+        if let Some((base, number)) = split_part(&source) {
+            groups.entry((source.parent().unwrap().to_path_buf(), base)).or_default().push((number, source));
+        } else {
+            joined_sources.push(source);
+        }
+    }
+    for ((dir, base), mut parts) in groups {
+        if parts.len() == 1 {
+            joined_sources.push(parts.pop().unwrap().1);
+            continue;
+        }
+        parts.sort();
+        let joined = dir.join(format!("{base}_joined.mkv"));
+        let frames = parts.iter().map(|(_, part)| count_frames(part)).sum::<Option<u32>>();
+        if !is_valid_intermediate(&joined, frames) {
+            check_part_layouts(&parts);
+            println_log!("Joining {} parts into {}", parts.len(), joined.display());
+            let mut arguments: Vec<String> = vec_into!["-q", "-o", path_str(&joined)];
+            for (i, (_, part)) in parts.iter().enumerate() {
+                if i > 0 {
+                    arguments.push("+".into());
+                }
+                arguments.push(path_str(part));
+            }
+            let status = Command::new(get_binary("mkvmerge")).args(arguments).logged().status().unwrap();
+            // mkvmerge exits with 1 for warnings and 2 for errors
+            if status.code() == Some(2) || joined.try_exists().is_ok_and(|b| b == false) {
+                let _ = fs::remove_file(&joined);
+                panic!("mkvmerge failed to join the parts of {base}!");
+            }
+        }
+        joined_sources.push(joined);
+    }
+    joined_sources
 }
 
 #[rustfmt::skip]
 fn extract_episode_number(base: &OsStr, pattern: String, season: Option<String>) -> Result<String, String> {
-    let temp_str = base.to_str().unwrap();
+    let temp_str = path_str(&base);
     if pattern == "1" || pattern == "2" {
         let patterns = [
             Regex::new(format!("(?i)S{}E(\\d{{2}})(?!\\d)", season.as_ref().unwrap()).as_str()).unwrap(),
@@ -343,7 +865,7 @@ fn extract_episode_number(base: &OsStr, pattern: String, season: Option<String>)
         ];
         let mut regex_match: Option<String> = None;
         for pattern in patterns {
-            let result = pattern.captures(temp_str);
+            let result = pattern.captures(&temp_str);
             if result.is_err() || result.as_ref().unwrap().is_none() { continue; }
             let caps = result.unwrap().unwrap();
             regex_match = Some(caps.get(1).unwrap().as_str().to_owned());
@@ -371,44 +893,157 @@ fn enc_opus(source: &PathBuf, stream: &mut Probe, bitrate: &str) {
     audio_path.set_extension(format!("{index}.{lang}.opus"));
     stream.file = audio_path.clone();
     if s.start_pts != 0 {
-        stream.offset += s.start_pts.clone() as i32;
+        stream.offset += stream.start_ms();
     }
-    if audio_path.try_exists().is_ok_and(|r| r == false) {
+    // a 0 byte file is left behind by an earlier failed encode
+    if audio_path.try_exists().is_ok_and(|r| r == false) || audio_path.metadata().is_ok_and(|m| m.len() == 0) {
         #[rustfmt::skip]
         let mut flac_pipe = Command::new(get_binary("ffmpeg"))
-            .args(["-i",source.to_str().unwrap(),"-map",format!("0:{index}").as_str(),"-v","16","-hide_banner","-f","flac","-"])
+            .args(["-i",path_str(&source).as_str(),"-map",format!("0:{index}").as_str(),"-v","16","-hide_banner","-f","flac","-"])
             .stdout(Stdio::piped())
-            .spawn()
+            .logged().spawn()
             .expect("FFmpeg broken pipe!");
-        let mut opusenc = Command::new(get_binary("opusenc"))
-            .args(["--bitrate", bitrate, "-", audio_path.to_str().unwrap()])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
+        // ffmpeg writes straight into opusenc through an OS pipe
+        let flac = Stdio::from(flac_pipe.stdout.take().unwrap());
+        let opusenc_status = Command::new(get_binary("opusenc"))
+            .args(["--bitrate", bitrate, "-", path_str(&audio_path).as_str()])
+            .stdin(flac)
+            .stdout(Stdio::null())
+            .logged().status()
             .expect("opusenc encode failed!");
-        if let Some(ref mut stdout) = flac_pipe.stdout {
-            if let Some(ref mut stdin) = opusenc.stdin {
-                let buf = BufReader::new(stdout);
-                let writer = BufWriter::new(stdin);
-                futures::executor::block_on(futures::io::copy(
-                    &mut asyncio::new(buf),
-                    &mut asyncio::new(writer),
-                ))
-                .unwrap();
-            }
+        let ffmpeg_status = flac_pipe.wait().unwrap();
+        if !ffmpeg_status.success() || !opusenc_status.success() || !is_valid_audio(&audio_path) {
+            let _ = fs::remove_file(&audio_path);
+            let _ = fs::remove_file(cache_path(PathBuf::from(format!("{}.ffprobe", audio_path.display()))));
+            panic!("Failed to encode audio track {index} of {} to opus! (ffmpeg: {ffmpeg_status}, opusenc: {opusenc_status})", source.display());
+        }
+    }
+}
+
+// average bitrate from the packet sizes of a track without a bitrate tag, cached next to the .ffprobe
+fn measured_bit_rate(stream: &Probe) -> u32 {
+    let cache = cache_path(PathBuf::from(format!("{}.{}.bps", stream.file.display(), stream.stream.index)));
+    if let Some(bps) = fs::read_to_string(&cache).ok().and_then(|b| b.trim().parse().ok()) {
+        return bps;
+    }
+    let duration = stream.duration();
+    if duration <= 0.0 {
+        return 0;
+    }
+    println_log!("Measuring the bitrate of audio track {} of {}", stream.stream.index, stream.file.display());
+    #[rustfmt::skip]
+    let output = Command::new("ffprobe")
+        .args(["-v","error","-select_streams",stream.stream.index.to_string().as_str(),"-show_entries","packet=size","-of","csv=p=0",path_str(&stream.file).as_str()])
+        .logged().output();
+    let Some(output) = output.ok().filter(|o| o.status.success()) else {
+        eprintln_log!("WARNING: Failed to measure the bitrate of audio track {} of {}", stream.stream.index, stream.file.display());
+        return 0;
+    };
+    let bytes: u64 = str::from_utf8(&output.stdout).unwrap().lines().filter_map(|l| l.trim().parse::<u64>().ok()).sum();
+    let bps = (bytes as f64 * 8.0 / duration) as u32;
+    File::create(cache).unwrap().write_all(bps.to_string().as_bytes()).unwrap();
+    bps
+}
+
+// opus bitrate in kbps for a track, None when it's already below it
+fn opus_bitrate(channels: u8, bps: u32) -> Option<&'static str> {
+    if (channels < 6 && bps == 0) || (channels < 6 && bps > 128000) {
+        Some("128")
+    } else if (channels == 6 && bps == 0) || (channels == 6 && bps > 256000) {
+        Some("256")
+    } else if (channels > 6 && bps == 0) || (channels > 6 && bps > 320000) {
+        Some("320")
+    } else {
+        None
+    }
+}
+
+// Re-encodes a track to opus from whichever file it was probed from, tracks that are already small enough are kept as is
+fn encode_audio(stream: &mut Probe) {
+    let source = stream.file.clone();
+    let channels = stream.stream.channels.unwrap();
+    let bps: u32 = match stream.bit_rate() {
+        0 => measured_bit_rate(stream),
+        bps => bps,
+    };
+    let Some(bitrate) = opus_bitrate(channels, bps) else {
+        return;
+    };
+    enc_opus(&source, stream, bitrate);
+    stream.stream.index = 0;
+    stream.stream.tags.bps = Some(format!("{bitrate}000"));
+}
+
+// Probes the file directly, the cached ffprobe result of a broken file is useless
+fn is_valid_audio(file: &PathBuf) -> bool {
+    if file.metadata().is_ok_and(|m| m.len() > 0) == false {
+        return false;
+    }
+    #[rustfmt::skip]
+    let output = Command::new("ffprobe")
+        .args(["-v","error","-print_format","json","-show_streams","-hide_banner","-i",path_str(&file).as_str()])
+        .logged().output();
+    if output.is_err() || !output.as_ref().unwrap().status.success() {
+        return false;
+    }
+    let probe = serde_json::from_slice::<FileProbe>(&output.unwrap().stdout);
+    probe.is_ok_and(|p| p.streams.iter().any(|s| s.codec_type == "audio"))
+}
+
+// Counts packets instead of decoding, that's enough to tell a truncated encode apart
+fn count_frames(file: &PathBuf) -> Option<u32> {
+    #[rustfmt::skip]
+    let output = Command::new("ffprobe")
+        .args(["-v","error","-select_streams","v:0","-count_packets","-show_entries","stream=nb_read_packets","-of","csv=p=0",path_str(&file).as_str()])
+        .logged().output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse::<u32>().ok()
+}
+
+// a crash or a full disk leaves truncated intermediates behind, which are removed so they're made again instead of being reused
+fn is_valid_intermediate(path: &PathBuf, expected_frames: Option<u32>) -> bool {
+    if path.try_exists().is_ok_and(|b| b == false) {
+        return false;
+    }
+    let frames = count_frames(path);
+    let valid = match expected_frames {
+        Some(expected) => frames == Some(expected),
+        None => frames.is_some_and(|f| f > 0),
+    };
+    if !valid {
+        let found = frames.map_or("no readable".to_string(), |f| f.to_string());
+        let expected = expected_frames.map_or(String::new(), |f| format!(" of {f}"));
+        eprintln_log!("WARNING: {} is incomplete with {found} frames{expected}, creating it again", path.display());
+        if let Err(e) = fs::remove_file(path) {
+            panic!("Failed to remove incomplete {}: {e}", path.display());
         }
-        opusenc.wait().unwrap();
     }
+    valid
+}
+
+// total frame count of an av1an scenes file, None until it's been made
+fn scene_frames(scenes: &PathBuf) -> Option<u32> {
+    let file = File::open(scenes).ok()?;
+    serde_json::from_reader::<_, ScenesInfo>(file).ok().map(|s| s.frames)
 }
 
 #[rustfmt::skip]
 fn get_medium_streams(ffprobe_input: &FileProbe, file_path: &PathBuf, medium: &str, offset: Option<i32>) -> Vec<Probe> {
-    let result = ffprobe_input.streams.iter().filter(|s| s.codec_type == medium).map(|s| Probe {stream: s.clone(),file: file_path.clone(),offset: offset.unwrap_or(0),index: None});
+    let result = ffprobe_input.streams.iter().filter(|s| s.codec_type == medium).map(|s| Probe {stream: s.clone(),file: file_path.clone(),offset: offset.unwrap_or(0),index: None,src2: offset.is_some()});
     return Vec::from_iter(result);
 }
 
+// codecs missing from the list sort after every listed one
+// ffprobe names PCM by sample format (pcm_s16le, pcm_s24le, ...), so an entry also matches its whole family
+fn codec_priority(order: &Vec<String>, codec_name: &String) -> usize {
+    let family = codec_name.split('_').next().unwrap();
+    order.iter().position(|c| c.eq_ignore_ascii_case(codec_name) || c.eq_ignore_ascii_case(family)).unwrap_or(order.len())
+}
+
 #[rustfmt::skip]
-fn compare_streams(probe1: Probe, probe2: Probe) -> Probe {
+fn compare_streams(probe1: Probe, probe2: Probe, args: &Args) -> Probe {
     let stream1 = &probe1.stream;
     let stream2 = &probe2.stream;
     if stream1.codec_type == "audio" {
@@ -417,10 +1052,8 @@ fn compare_streams(probe1: Probe, probe2: Probe) -> Probe {
         if channels1 != channels2 {
             return if channels1 > channels2 { probe1 } else { probe2 };
         }
-        // TODO: figure out what codec is PCM, Wave64, or Wave
-        let codec_priority: Vec<&'static str> = vec!["mlp", "truehd", "flac", "PCM", "Wave64", "Wave", "eac3", "aac", "opus", "ac3", "vorbis", "mp3", "mp2", "mp1"];
-        let codec1_piority = codec_priority.iter().position(|c| *c == stream1.codec_name).unwrap_or(14);
-        let codec2_piority = codec_priority.iter().position(|c| *c == stream2.codec_name).unwrap_or(14);
+        let codec1_piority = codec_priority(&args.audio_codec_priority, &stream1.codec_name);
+        let codec2_piority = codec_priority(&args.audio_codec_priority, &stream2.codec_name);
         if codec1_piority != codec2_piority {
             return if codec1_piority < codec2_piority { probe1 } else { probe2 };
         }
@@ -429,12 +1062,14 @@ fn compare_streams(probe1: Probe, probe2: Probe) -> Probe {
         if bps1 != bps2 {
             return if bps1 > bps2 { probe1 } else { probe2 };
         }
+        if probe1.src2 != probe2.src2 {
+            return if probe1.src2 == (args.audio_prefer == "source2") { probe1 } else { probe2 };
+        }
         return probe1;
     } else {
-        let codec_priority: Vec<&'static str> = vec!["ass", "subrip", "hdmv_pgs_subtitle"];
-        let codec1_piority = codec_priority.iter().position(|c| *c == stream1.codec_name).unwrap_or(2);
-        let codec2_piority = codec_priority.iter().position(|c| *c == stream2.codec_name).unwrap_or(2);
-        return if codec1_piority < codec2_piority { probe2 } else { probe1 };
+        let codec1_piority = codec_priority(&args.sub_codec_priority, &stream1.codec_name);
+        let codec2_piority = codec_priority(&args.sub_codec_priority, &stream2.codec_name);
+        return if codec2_piority < codec1_piority { probe2 } else { probe1 };
     }
 }
 
@@ -455,37 +1090,90 @@ fn get_title(lang: &Language, title: &String) -> String {
     return final_title;
 }
 
-fn filter_redundant_tracks(streams: &mut Vec<Probe>) -> Vec<Probe> {
-    let mut unique_tracks: HashMap<Track, Probe> = HashMap::new();
+// languages missing from the list (und, yue, etc.) sort last in their original order
+fn language_priority(order: &Vec<&'static str>, probe: &Probe) -> usize {
+    let code = probe.language().to_639_3();
+    order.iter().position(|l| *l == code).unwrap_or(order.len())
+}
+
+// keeps the position of the first occurrence so the input order survives deduplication
+fn filter_redundant_tracks(streams: &mut Vec<Probe>, keep_titles: bool, args: &Args) -> Vec<Probe> {
+    let mut unique_tracks: Vec<(Track, Probe)> = Vec::new();
     for stream in streams {
         let s = stream.stream.clone();
         let origin_lang = stream.language();
         let origin_title = s.tags.title.clone().unwrap_or("".to_string());
-        let new_title = get_title(&origin_lang, &origin_title);
+        // mkvmerge can't be given an empty track name, so untitled tracks are named after their language either way
+        let new_title = if keep_titles && !origin_title.trim().is_empty() {
+            origin_title.trim().to_string()
+        } else {
+            get_title(&origin_lang, &origin_title)
+        };
         let _ = stream.stream.tags.title.insert(new_title.clone());
         let key = Track {
             language: origin_lang,
             title: new_title,
             forced: s.disposition.forced == 1,
         };
-        if unique_tracks.keys().find(|e| **e == key).is_none() {
-            unique_tracks.insert(key, stream.clone());
+        let position = unique_tracks.iter().position(|e| e.0 == key);
+        if position.is_none() {
+            unique_tracks.push((key, stream.clone()));
         } else {
-            let stream2 = unique_tracks.get(&key).unwrap();
-            let winner = compare_streams(stream.clone(), stream2.clone());
-            unique_tracks.remove_entry(&key);
-            unique_tracks.insert(key, winner);
+            let stream2 = &unique_tracks[position.unwrap()].1;
+            let winner = compare_streams(stream.clone(), stream2.clone(), args);
+            unique_tracks[position.unwrap()].1 = winner;
         }
     }
-    return Vec::from_iter(unique_tracks.values().cloned());
+    return Vec::from_iter(unique_tracks.into_iter().map(|e| e.1));
+}
+
+// First audio track as 8kHz mono samples, mean removed so a DC offset doesn't count as a match
+fn audio_samples(file: &PathBuf, duration: &str) -> Vec<f32> {
+    #[rustfmt::skip]
+    let pcm = Command::new(get_binary("ffmpeg"))
+        .args(["-hide_banner", "-loglevel", "error", "-i", path_str(file).as_str(), "-t", duration, "-map", "0:a:0", "-ac", "1", "-ar", "8000", "-f", "f32le", "-"])
+        .logged().output().unwrap().stdout;
+    let samples: Vec<f32> = pcm.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+    let mean = samples.iter().sum::<f32>() / samples.len().max(1) as f32;
+    samples.into_iter().map(|s| s - mean).collect()
+}
+
+// Offset in seconds of src2's audio relative to the reference, found with an FFT cross-correlation
+// and searched up to 30 seconds either way
+fn get_audio_offset(file_path: &PathBuf, src2_path: &PathBuf, duration: &str) -> f32 {
+    let rate = 8000;
+    let reference = audio_samples(file_path, duration);
+    let source = audio_samples(src2_path, duration);
+    if reference.is_empty() || source.is_empty() {
+        panic!("Failed to determine offsets, couldn't read audio from {}", src2_path.display());
+    }
+    // zero padded to avoid the correlation wrapping around
+    let size = (reference.len() + source.len()).next_power_of_two();
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(size);
+    let spectrum = |samples: &Vec<f32>| {
+        let mut buffer: Vec<Complex<f32>> = samples.iter().map(|s| Complex::new(*s, 0.0)).collect();
+        buffer.resize(size, Complex::new(0.0, 0.0));
+        fft.process(&mut buffer);
+        buffer
+    };
+    let (reference, source) = (spectrum(&reference), spectrum(&source));
+    let mut correlation: Vec<Complex<f32>> = reference.iter().zip(source.iter()).map(|(r, s)| r * s.conj()).collect();
+    planner.plan_fft_inverse(size).process(&mut correlation);
+    let max_lag = (30 * rate).min(size as i64 / 2 - 1);
+    let lag = (-max_lag..=max_lag).max_by(|a, b| {
+        let (a, b) = (a.rem_euclid(size as i64) as usize, b.rem_euclid(size as i64) as usize);
+        correlation[a].re.total_cmp(&correlation[b].re)
+    }).unwrap();
+    lag as f32 / rate as f32
 }
 
 #[rustfmt::skip]
-fn get_offset(file_path: &PathBuf, src2_path: &PathBuf) -> i32 {
-    println!("Determining offsets for {}", src2_path.display());
-    let ref_clip = file_path.parent().unwrap().join(format!("{}_clip.mkv",file_path.file_stem().unwrap().to_str().unwrap()));
-    let src_clip = src2_path.parent().unwrap().join(format!("{}_clip.mkv",src2_path.file_stem().unwrap().to_str().unwrap()));
-    let offset_save = PathBuf::from(format!("{}.offset", src2_path.display()));
+fn get_offset(file_path: &PathBuf, src2_path: &PathBuf, args: &Args) -> i32 {
+    println_log!("Determining offsets for {}", src2_path.display());
+    let ref_clip = file_path.parent().unwrap().join(format!("{}_clip.mkv",path_str(&file_path.file_stem().unwrap())));
+    let src_clip = src2_path.parent().unwrap().join(format!("{}_clip.mkv",path_str(&src2_path.file_stem().unwrap())));
+    let offset_save = cache_path(PathBuf::from(format!("{}.offset", src2_path.display())));
     let offset: f32;
     if offset_save.try_exists().is_ok_and(|b| b == true) {
         let mut temp: String = String::new();
@@ -494,21 +1182,26 @@ fn get_offset(file_path: &PathBuf, src2_path: &PathBuf) -> i32 {
     } else {
         let start = "0".to_string();
         let duration = "60".to_string();
+        if args.sync_method == "audio" {
+            offset = get_audio_offset(file_path, src2_path, duration.as_str());
+            File::create(offset_save).unwrap().write_fmt(format_args!("{offset}")).unwrap();
+            return (offset * 1000.0) as i32
+        }
         if ref_clip.try_exists().is_ok_and(|v| v==false) {
             Command::new(get_binary("ffmpeg"))
-                .args(["-hide_banner", "-loglevel", "error", "-ss", start.as_str(), "-i", file_path.to_str().unwrap(), "-t", duration.as_str(), "-c:V", "libx264", "-q", "0", ref_clip.to_str().unwrap()])
-                .output().unwrap();
+                .args(["-hide_banner", "-loglevel", "error", "-ss", start.as_str(), "-i", path_str(&file_path).as_str(), "-t", duration.as_str(), "-c:V", "libx264", "-q", "0", path_str(&ref_clip).as_str()])
+                .logged().output().unwrap();
         }
         if src_clip.try_exists().is_ok_and(|v| v==false) {
             Command::new(get_binary("ffmpeg"))
-                .args(["-hide_banner", "-loglevel", "error", "-ss", start.as_str(), "-i", src2_path.to_str().unwrap(), "-t", duration.as_str(), "-c:V", "libx264", "-q", "0", src_clip.to_str().unwrap()])
-                .output().unwrap();
+                .args(["-hide_banner", "-loglevel", "error", "-ss", start.as_str(), "-i", path_str(&src2_path).as_str(), "-t", duration.as_str(), "-c:V", "libx264", "-q", "0", path_str(&src_clip).as_str()])
+                .logged().output().unwrap();
         }
         let position_info = Command::new(get_binary("ffmpeg"))
-            .args(["-i", ref_clip.to_str().unwrap(), "-i", src_clip.to_str().unwrap(), "-filter_complex", "signature=detectmode=fast:nb_inputs=2:th_xh=50", "-f", "null", "-"])
+            .args(["-i", path_str(&ref_clip).as_str(), "-i", path_str(&src_clip).as_str(), "-filter_complex", format!("signature=detectmode={}:nb_inputs=2:th_xh={}", args.sync_detect_mode, args.sync_threshold).as_str(), "-f", "null", "-"])
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
-            .output().unwrap();
+            .logged().output().unwrap();
         let re = Regex::new(r"(?i)matching of video 0 at ([0-9]+\.[0-9]+) and 1 at ([0-9]+\.[0-9]+)").unwrap();
         let result = re.captures(core::str::from_utf8(&position_info.stderr).unwrap())
             .expect("Failed to load regex!")
@@ -520,112 +1213,89 @@ fn get_offset(file_path: &PathBuf, src2_path: &PathBuf) -> i32 {
 }
 
 #[rustfmt::skip]
-fn get_info(file_path: &PathBuf, src2_paths: &Option<PathBuf>, args: &Args) -> (Vec<Probe>,Vec<Probe>,Vec<Probe>) {
-    println!("Collecting video information for {}", file_path.display());
-    let file_base = file_path.file_stem().unwrap();
-    let episode = extract_episode_number(&file_base, args.episode_pattern.clone(), Some(args.season.clone())).unwrap_or("".into());
-    let ffprobe_input = ffprobe(file_path);
+fn get_info(file_path: &PathBuf, src2_paths: &Option<Vec<PathBuf>>, args: &Args) -> (Vec<Probe>,Vec<Probe>,Vec<Probe>) {
+    println_log!("Collecting video information for {}", file_path.display());
+    let ffprobe_input = if is_script(file_path) {
+        if args.audio == "1" || args.subs == "1" {
+            panic!("{} is a VapourSynth script and has no audio or subtitle tracks, take them from --src2-directory with --audio 2 and --subs 2", file_path.display());
+        }
+        script_probe(file_path)
+    } else {
+        ffprobe(file_path)
+    };
     let mut video_streams = get_medium_streams(&ffprobe_input, &file_path, "video", None);
     let mut audio_streams = Vec::new();
     if args.audio == "1" || args.audio == "both" {
         audio_streams = get_medium_streams(&ffprobe_input, &file_path, "audio", None);
-        for mut stream in &mut audio_streams {
-            if args.original_audio {
-                break;
-            }
-            let audio = &stream.stream;
-            let channels = audio.channels.unwrap();
-            let bps: u32 = stream.bit_rate();
-            if (channels < 6 && bps == 0) || (channels < 6 && bps > 128000) {
-                enc_opus(&file_path, &mut stream, "128");
-                stream.stream.index = 0;
-                stream.stream.tags.bps = Some("128000".to_string());
-            } else if (channels == 6 && bps == 0) || (channels == 6 && bps > 256000) {
-                enc_opus(&file_path, &mut stream, "256");
-                stream.stream.index = 0;
-                stream.stream.tags.bps = Some("256000".to_string());
-            } else if (channels > 6 && bps == 0) || (channels < 6 && bps > 320000) {
-                enc_opus(&file_path, &mut stream, "320");
-                stream.stream.index = 0;
-                stream.stream.tags.bps = Some("320000".to_string());
-            }
+        if !args.original_audio {
+            audio_streams.iter_mut().for_each(encode_audio);
         }
     }
     let mut subtitle_streams = Vec::new();
     if args.subs == "1" || args.subs == "both" {
         subtitle_streams = get_medium_streams(&ffprobe_input, &file_path, "subtitle", None);
     }
-    if args.audio == "2" || args.audio == "both" || args.subs == "2" || args.subs == "both" {
-        for path in src2_paths.clone().unwrap().read_dir().unwrap() {
-            let dir_entry = path.unwrap();
-            let path = dir_entry.path();
-            if is_temporary_file(&dir_entry.file_name()) {
-                continue;
-            }
-            let base = path.file_stem().unwrap();
-            let episode_src2 = extract_episode_number(&base, args.episode_pattern.clone(), Some(args.season.clone())).unwrap_or("".into());
-            if (episode != "" && episode != episode_src2) || file_base != base {
-                continue;
-            }
-            let ffprobe_input = ffprobe(&dir_entry.path());
-            let mut v_streams = get_medium_streams(&ffprobe_input, &dir_entry.path(), "video", None);
+    let mut merge_candidates: Vec<Probe> = Vec::new();
+    if args.audio == "2" || args.audio == "both" || args.subs == "2" || args.subs == "both" || args.lehmer_merge {
+        // --src2-file or the files of --src2-directory that process_command matched to this episode
+        for path in src2_paths.clone().unwrap_or_default() {
+            let ffprobe_input = ffprobe(&path);
+            let mut v_streams = get_medium_streams(&ffprobe_input, &path, "video", None);
             let video_stream = v_streams.get(0);
             let offset;
             if args.sync != 0 {
                 offset = args.sync;
-            } else if video_stream.is_some() {
-                offset = get_offset(&file_path, &dir_entry.path());
+            } else if is_script(file_path) {
+                offset = 0;
+            } else if (args.sync_method == "video" && video_stream.is_some()) || (args.sync_method == "audio" && ffprobe_input.streams.iter().any(|s| s.codec_type == "audio")) {
+                offset = get_offset(&file_path, &path, &args);
             } else {
                 offset = 0;
             }
-            println!("{offset}");
+            println_log!("{offset}");
             if args.lehmer_merge {
-                v_streams = get_medium_streams(&ffprobe_input, &dir_entry.path(), "video", Some(offset));
-                video_streams.append(&mut v_streams);
+                v_streams = get_medium_streams(&ffprobe_input, &path, "video", Some(offset));
+                merge_candidates.append(&mut v_streams);
             }
             if args.audio == "2" || args.audio == "both" {
-                let mut a_streams = get_medium_streams(&ffprobe_input, &dir_entry.path(), "audio", Some(offset));
-                for mut stream in &mut audio_streams {
-                    if args.original_audio {
-                        break;
-                    }
-                    let audio = &stream.stream;
-                    let channels = audio.channels.unwrap();
-                    let bps: u32 = stream.bit_rate();
-                    if (channels < 6 && bps == 0) || (channels < 6 && bps > 128000) {
-                        enc_opus(&file_path, &mut stream, "128");
-                        stream.stream.index = 0;
-                        stream.stream.tags.bps = Some("128000".to_string());
-                    } else if (channels == 6 && bps == 0) || (channels == 6 && bps > 256000) {
-                        enc_opus(&file_path, &mut stream, "256");
-                        stream.stream.index = 0;
-                        stream.stream.tags.bps = Some("256000".to_string());
-                    } else if (channels > 6 && bps == 0) || (channels < 6 && bps > 320000) {
-                        enc_opus(&file_path, &mut stream, "320");
-                        stream.stream.index = 0;
-                        stream.stream.tags.bps = Some("320000".to_string());
-                    }
+                let mut a_streams = get_medium_streams(&ffprobe_input, &path, "audio", Some(offset));
+                // only the new tracks, the primary ones were already handled above
+                if !args.original_audio {
+                    a_streams.iter_mut().for_each(encode_audio);
                 }
                 audio_streams.append(&mut a_streams);
             }
             if args.subs == "2" || args.subs == "both" {
-                let mut s_streams = get_medium_streams(&ffprobe_input, &dir_entry.path(), "subtitle", Some(offset));
-                subtitle_streams.append(&mut s_streams);
+                // a --sync offset was given on purpose, only detected ones are checked
+                if args.sync == 0 && args.subtitle_sync_tolerance.is_some_and(|tolerance| offset.unsigned_abs() > tolerance) {
+                    eprintln_log!("WARNING: {} is {offset} ms off, more than --subtitle-sync-tolerance, leaving out its subtitles", path.display());
+                } else {
+                    let mut s_streams = get_medium_streams(&ffprobe_input, &path, "subtitle", Some(offset));
+                    subtitle_streams.append(&mut s_streams);
+                }
             }
         }
     }
-    audio_streams = filter_redundant_tracks(&mut audio_streams);
-    let audio_order: Vec<&'static str> = vec!["jpn", "eng", "spa", "ara", "fra", "deu", "ita", "por", "pol", "nld", "nob", "fin", "tur", "swe", "ell", "heb", "ron", "ind", "tha", "kor", "dan", "chi", "vie", "ukr", "rus", "hun", "ces", "hrv", "msa", "hin"];
-    audio_streams.sort_by(|a, b| {audio_order.iter().position(|l| *l == a.language().to_639_3()).unwrap_or(audio_order.len()).cmp(&audio_order.iter().position(|l| *l == b.language().to_639_3()).unwrap_or(audio_order.len()))});
-    subtitle_streams = filter_redundant_tracks(&mut subtitle_streams);
-    let sub_order: Vec<&'static str> = vec!["eng", "spa", "ara", "fra", "deu", "ita", "jpn", "por", "pol", "nld", "nob", "fin", "tur", "swe", "ell", "heb", "ron", "ind", "tha", "kor", "dan", "chi", "vie", "ukr", "rus", "hun", "ces", "hrv", "msa", "hin"];
-    subtitle_streams.sort_by(|a, b| {sub_order.iter().position(|l| *l == a.language().to_639_3()).unwrap_or(sub_order.len()).cmp(&sub_order.iter().position(|l| *l == b.language().to_639_3()).unwrap_or(audio_order.len()))});
-    // obnoxiously long sort, TODO: make readable
+    if args.lehmer_merge {
+        let partner = select_merge_partner(&video_streams[0], merge_candidates);
+        video_streams.truncate(1);
+        video_streams.push(partner);
+    }
+    audio_streams = filter_redundant_tracks(&mut audio_streams, args.keep_original_audio_names, args);
+    let audio_order: Vec<&'static str> = vec!["jpn", "eng", "spa", "ara", "fra", "deu", "ita", "por", "pol", "nld", "nob", "fin", "tur", "swe", "ell", "heb", "ron", "ind", "tha", "kor", "dan", "zho", "vie", "ukr", "rus", "hun", "ces", "hrv", "msa", "hin"];
+    if !args.preserve_track_order {
+        audio_streams.sort_by_key(|a| language_priority(&audio_order, a));
+    }
+    subtitle_streams = filter_redundant_tracks(&mut subtitle_streams, false, args);
+    let sub_order: Vec<&'static str> = vec!["eng", "spa", "ara", "fra", "deu", "ita", "jpn", "por", "pol", "nld", "nob", "fin", "tur", "swe", "ell", "heb", "ron", "ind", "tha", "kor", "dan", "zho", "vie", "ukr", "rus", "hun", "ces", "hrv", "msa", "hin"];
+    if !args.preserve_track_order {
+        subtitle_streams.sort_by_key(|s| language_priority(&sub_order, s));
+    }
     let mut ainfo: Vec<Probe> = Vec::new();
     let mut sinfo: Vec<Probe> = Vec::new();
     let mut vinfo: Vec<Probe> = Vec::new();
-    let mut file_to_source_map: HashMap<PathBuf, u8> = HashMap::new();
-    let mut source_index: u8 = 0;
+    let mut file_to_source_map: HashMap<PathBuf, u32> = HashMap::new();
+    let mut source_index: u32 = 0;
     for (idx, entry) in audio_streams.iter().enumerate() {
         if !file_to_source_map.contains_key(&entry.file.to_path_buf()) {
             file_to_source_map.insert(entry.file.to_path_buf(), source_index);
@@ -636,7 +1306,7 @@ fn get_info(file_path: &PathBuf, src2_paths: &Option<PathBuf>, args: &Args) -> (
         ainfo.insert(idx, info.clone());
     }
     file_to_source_map.clear();
-    let next_index: u8 = source_index;
+    let next_index: u32 = source_index;
     source_index = 0;
     for (idx, entry) in subtitle_streams.iter().enumerate() {
         if !file_to_source_map.contains_key(&entry.file.to_path_buf()) {
@@ -644,7 +1314,7 @@ fn get_info(file_path: &PathBuf, src2_paths: &Option<PathBuf>, args: &Args) -> (
             source_index += 1;
         }
         let mut info = entry.clone();
-        info.index = Some(*file_to_source_map.get(&info.file.to_path_buf()).unwrap() + next_index);
+        info.index = Some(file_to_source_map.get(&info.file.to_path_buf()).unwrap().checked_add(next_index).expect("Too many audio and subtitle files!"));
         sinfo.insert(idx, info.clone());
     }
     for (idx, entry) in video_streams.iter().enumerate() {
@@ -653,25 +1323,42 @@ fn get_info(file_path: &PathBuf, src2_paths: &Option<PathBuf>, args: &Args) -> (
     (vinfo, ainfo, sinfo)
 }
 
-fn get_encoder_version(encoder: &str) -> Result<String, String> {
+// lehmer merge only takes 2 sources, picks the highest resolution src2 video then the closest in duration
+fn select_merge_partner(source: &Probe, mut candidates: Vec<Probe>) -> Probe {
+    if candidates.is_empty() {
+        panic!("No 2nd source video found to lehmer merge with {}!", source.file.display());
+    }
+    let duration = source.duration();
+    let distance = |probe: &Probe| (probe.duration() - duration).abs();
+    candidates.sort_by(|a, b| b.resolution().cmp(&a.resolution()).then(distance(a).total_cmp(&distance(b))));
+    if candidates.len() > 1 && candidates[0].resolution() == candidates[1].resolution() && (distance(&candidates[0]) - distance(&candidates[1])).abs() < 0.5 {
+        #[rustfmt::skip]
+        panic!("Ambiguous 2nd source for lehmer merge, {} and {} match equally well! Only keep one in the 2nd source directory.", candidates[0].file.display(), candidates[1].file.display());
+    }
+    println_log!("Lehmer merge 2nd source: {}", candidates[0].file.display());
+    candidates.swap_remove(0)
+}
+
+fn get_encoder_version(encoder: &str, args: &Args) -> Result<String, String> {
     if encoder == "rav1e" {
         let output = Command::new(get_binary("rav1e"))
             .arg("-V")
-            .output()
+            .logged().output()
             .map_err(|_| "Failed to get encoder version!");
         #[rustfmt::skip]
         return Ok(format!("rav1e v{}", String::from_utf8(output.unwrap().stdout).unwrap().split(" ").nth(1).unwrap().to_string()));
     } else if encoder == "svt-av1" {
         let output = Command::new(get_binary("SvtAv1EncApp"))
             .arg("--version")
-            .output()
+            .logged().output()
             .map_err(|_| "Failed to get encoder version!");
+        let name = if svt_psy(args) { "svt-av1-psy" } else { "svt-av1" };
         #[rustfmt::skip]
-        return Ok(format!("svt-av1-psy {}", String::from_utf8(output.unwrap().stdout).unwrap().split(' ').nth(1).unwrap().to_string()));
+        return Ok(format!("{name} {}", String::from_utf8(output.unwrap().stdout).unwrap().split(' ').nth(1).unwrap().to_string()));
     } else if encoder == "opusenc" {
         let output = Command::new(get_binary("opusenc"))
             .arg("--version")
-            .output()
+            .logged().output()
             .map_err(|_| "Failed to get encoder version!");
         let mut result = String::from_utf8(output.unwrap().stdout).unwrap();
         result = result.split("libopus").nth(1).unwrap().to_string();
@@ -682,12 +1369,31 @@ fn get_encoder_version(encoder: &str) -> Result<String, String> {
     }
 }
 
+// full version line, forks put the git commit in it which the short version drops
+fn get_encoder_build(encoder: &str) -> Result<String, String> {
+    let (binary, flag) = if encoder == "rav1e" {
+        ("rav1e", "-V")
+    } else if encoder == "svt-av1" {
+        ("SvtAv1EncApp", "--version")
+    } else if encoder == "opusenc" {
+        ("opusenc", "--version")
+    } else {
+        return Err("Encoder not supported!".to_string());
+    };
+    let output = Command::new(get_binary(binary))
+        .arg(flag)
+        .logged().output()
+        .map_err(|_| "Failed to get encoder version!")?;
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    Ok(stdout.lines().next().unwrap_or("").trim().to_string())
+}
+
 #[rustfmt::skip]
 fn get_encoder_params(args: &Args, vinfo: &Vec<Probe>, speed: Option<u8>, quantizer: Option<f32>, encoder: Option<&str>, display: bool) -> String {
     let speed = speed.unwrap_or(args.speed);
     let q = quantizer.unwrap_or(args.quantizer);
     let encoder = encoder.unwrap_or(&args.encoder);
-    let range = quantizer_range(args.quantizer_range.clone(), encoder.to_string());
+    let range = args.quantizer_range();
     let q_display = format!("{:.1}-{:.1}", range[0], range[1]);
     let quantizer = if display {
         q_display
@@ -696,13 +1402,15 @@ fn get_encoder_params(args: &Args, vinfo: &Vec<Probe>, speed: Option<u8>, quanti
     };
     let params = format!(" {}", args.parameters.as_deref().unwrap_or(" ".into()));
     let (cr, matrix, transfer, primaries) = vinfo[0].color_data(args.encoder == "rav1e");
+    let rate = rate_control_params(args, encoder, quantizer).join(" ");
+    let tiles: String = tile_params(args, encoder).iter().map(|p| format!(" {p}")).collect();
     let result = if encoder == "svt-av1" {
-        format!("--crf {quantizer}{params} --preset {speed} --tune 3 --sharpness 2 --variance-boost-strength 4 --variance-octile 4 --frame-luma-bias 100 --keyint 0 --enable-dlf 2 --enable-cdef 0 --enable-restoration 0 --enable-tf 0 --color-range {cr} --matrix-coefficients {matrix} --transfer-characteristics {transfer} --color-primaries {primaries}")
+        format!("{rate}{params} --preset {speed}{tiles} {} --color-range {cr} --matrix-coefficients {matrix} --transfer-characteristics {transfer} --color-primaries {primaries}", svt_params(args).join(" "))
     } else if encoder == "rav1e" {
-        let tiles = args.tiles;
-        format!("--quantizer {quantizer}{params} -s {speed} --tiles {tiles} --keyint 0 --no-scene-detection --range {cr} --matrix {matrix} --transfer {transfer} --primaries {primaries}")
-    } else if encoder == "x264" {
-        format!("-q 0")
+        format!("{rate}{params} -s {speed}{tiles} --keyint {} --no-scene-detection --range {cr} --matrix {matrix} --transfer {transfer} --primaries {primaries}", args.keyint)
+    } else if encoder == args.grain_ref_encoder {
+        let default = if encoder == "x265" { "--lossless" } else { "-q 0" };
+        args.grain_ref_params.clone().unwrap_or(default.to_string())
     } else {
         String::new()
     };
@@ -712,19 +1420,96 @@ fn get_encoder_params(args: &Args, vinfo: &Vec<Probe>, speed: Option<u8>, quanti
     return result;
 }
 
+// whether SvtAv1EncApp is the psy fork, from --svt-flavor or its build string the first time it matters
+static SVT_PSY: OnceLock<bool> = OnceLock::new();
+
+fn detect_svt_psy(args: &Args) -> bool {
+    let version = get_encoder_build("svt-av1").unwrap_or_default();
+    let psy = version.to_lowercase().contains("psy");
+    // --no-psy leaves them out on either build
+    if !psy && !args.no_psy {
+        eprintln_log!("WARNING: {version} isn't svt-av1-psy, PSY-only parameters (--tune 3, --frame-luma-bias) are left out");
+    }
+    psy
+}
+
+fn svt_psy(args: &Args) -> bool {
+    *SVT_PSY.get_or_init(|| match args.svt_flavor.as_str() {
+        "psy" => true,
+        "mainline" => false,
+        _ => detect_svt_psy(args),
+    })
+}
+
+// svt-av1-psy tuning shared by every svt-av1 pass, the in-loop filters default to what suits clean anime
+#[rustfmt::skip]
+fn svt_params(args: &Args) -> Vec<String> {
+    let filters = vec_into![
+        "--keyint", args.keyint.to_string(),
+        "--enable-dlf", args.dlf.to_string(),
+        "--enable-cdef", args.cdef.to_string(),
+        "--enable-restoration", args.restoration.to_string()
+    ];
+    if args.no_psy {
+        return filters;
+    }
+    // mainline has no tune 3 or luma bias, and variance boost has to be enabled there
+    let tuning: Vec<String> = if svt_psy(args) {
+        vec_into!["--tune", "3", "--sharpness", "2", "--variance-boost-strength", "4", "--variance-octile", "4", "--frame-luma-bias", "100"]
+    } else {
+        vec_into!["--tune", "0", "--sharpness", "2", "--enable-variance-boost", "1", "--variance-boost-strength", "4", "--variance-octile", "4"]
+    };
+    [tuning, filters, vec_into!["--enable-tf", args.temporal_filtering.to_string()]].concat()
+}
+
+fn rate_control_params(args: &Args, encoder: &str, quantizer: String) -> Vec<String> {
+    if let Some(bitrate) = args.bitrate {
+        if encoder == "rav1e" {
+            vec_into!["--bitrate", bitrate.to_string()]
+        } else {
+            vec_into!["--rc", "1", "--tbr", bitrate.to_string()]
+        }
+    } else if encoder == "rav1e" {
+        vec_into!["--quantizer", quantizer]
+    } else {
+        vec_into!["--crf", quantizer]
+    }
+}
+
+fn tile_params(args: &Args, encoder: &str) -> Vec<String> {
+    if encoder == "rav1e" && args.tile_rows.is_none() && args.tile_cols.is_none() {
+        return vec_into!["--tiles", args.tiles.to_string()];
+    }
+    let cols_flag = if encoder == "rav1e" { "--tile-cols" } else { "--tile-columns" };
+    let mut params: Vec<String> = Vec::new();
+    if let Some(rows) = args.tile_rows {
+        params.append(&mut vec_into!["--tile-rows", rows.to_string()]);
+    }
+    if let Some(cols) = args.tile_cols {
+        params.append(&mut vec_into![cols_flag, cols.to_string()]);
+    }
+    params
+}
+
 fn get_grain_string(args: &Args) -> String {
     if args.diff_grain {
         return if args.lehmer_merge {
-            "diff + lehmer merge with vs-denoise: \"lowpass = lambda i: box_blur(i, passes=2)\""
-                .to_string()
+            format!("diff + lehmer merge with vs-denoise: \"lowpass = {}\"", get_lowpass_string(args))
         } else {
             "diff".to_string()
         };
     } else {
-        return format!("--iso {}", args.photon_noise);
+        let chroma = if args.grain_chroma { " --chroma" } else { "" };
+        let params: String = args.grain_params().iter().map(|p| format!(" {p}")).collect();
+        return format!("--iso {}{chroma}{params}", args.photon_noise);
     }
 }
 
+// shared by merge_script and get_grain_string so the tags match the script
+fn get_lowpass_string(args: &Args) -> String {
+    format!("lambda i: box_blur(i, passes={})", args.merge_passes)
+}
+
 fn get_denoise_string(args: &Args) -> String {
     let mut denoise_string = format!(
         "strength={}, tr=2, sr=[3,2,2], planes=[0,1,2]",
@@ -736,28 +1521,34 @@ fn get_denoise_string(args: &Args) -> String {
     return denoise_string;
 }
 
+// shared by create_vpy_script and get_filter_string so the tags match the script
+fn get_deband_string(args: &Args) -> String {
+    let mut deband_string = format!("thr={}, planes=[0,1,2]", args.deband);
+    if args.retinex {
+        deband_string = format!("grain=0, rg_mode={}, {deband_string}", args.retinex_rg_mode);
+        if let Some(sigma) = args.retinex_sigma {
+            deband_string.push_str(format!(", sigma={sigma}").as_str());
+        }
+    }
+    deband_string
+}
+
+// one part per active filter with its parameters in quotes, so every combination reads the same way
 fn get_filter_string(args: &Args) -> String {
-    let mut filter_string = String::new();
+    let mut filters: Vec<String> = Vec::new();
     if !args.no_denoise {
-        filter_string = format!("Denoise with vs-denoise: \"{}\"", get_denoise_string(&args));
+        filters.push(format!("denoise with vs-denoise: \"{}\"", get_denoise_string(&args)));
     }
     if args.dehalo {
-        if !filter_string.is_empty() {
-            filter_string.push_str(", dering with vs-dehalo: \"planes=[0,1,2]\"");
-        } else {
-            filter_string = String::from("Dering with vs-dehalo: \"planes=[0,1,2]\"");
-        }
+        filters.push(String::from("dering with vs-dehalo: \"planes=[0,1,2]\""));
     }
-    if !filter_string.is_empty() {
-        filter_string.push_str(", deband with vs-deband");
-    } else {
-        filter_string = String::from("Deband with vs-deband");
-    }
-    if args.retinex {
-        filter_string.push_str(", grain=0\" + retinex mask: \"rg_mode=0");
+    if !args.no_deband {
+        let mask = if args.retinex { " and a retinex mask" } else { "" };
+        filters.push(format!("deband with vs-deband{mask}: \"{}\"", get_deband_string(&args)));
     }
-    filter_string.push_str("\", dither with vs-tools");
-    return filter_string;
+    filters.push(String::from("dither with vs-tools"));
+    let filter_string = filters.join(", ");
+    filter_string[..1].to_uppercase() + &filter_string[1..]
 }
 
 fn get_rescale_string(args: &Args) -> String {
@@ -778,10 +1569,33 @@ fn get_rescale_string(args: &Args) -> String {
     return rescale_string;
 }
 
-#[rustfmt::skip]
+// the lossy conversion is only reported for the first path
+static WARNED_NON_UTF8: AtomicBool = AtomicBool::new(false);
+
+// External tools and scripts take strings, paths that aren't valid UTF-8 are converted lossily instead of panicking
+fn path_str<P: AsRef<Path>>(path: P) -> String {
+    let path = path.as_ref();
+    if path.to_str().is_none() && !WARNED_NON_UTF8.swap(true, Ordering::Relaxed) {
+        eprintln_log!("WARNING: {} is not valid UTF-8, it will be converted lossily and external tools may fail to find it.", path.display());
+    }
+    path.to_string_lossy().to_string()
+}
+
+// quoted python string literal for a path, json escaping is valid python and survives quotes in file names
+fn py_path(path: &PathBuf) -> String {
+    serde_json::to_string(&path_str(path)).unwrap()
+}
+
+#[rustfmt::skip]
 fn get_source_string(file: &PathBuf, args: &Args, format: Option<String>) -> String {
-    if args.source_filter == "lsmash" {
-        let pass1 = format!("lsmas.LWLibavSource(r'{}', cachedir=r'{}', prefer_hw=3", file.display(), args.input_directory.display());
+    if is_script(file) {
+        // runs the script in this environment and takes the clip it outputs
+        format!("(__import__('runpy').run_path({}, run_name='__vapoursynth__'), vs.get_output(0).clip)[1]", py_path(&abs(file).unwrap()))
+    } else if is_y4m(file) {
+        // the source filters need an index, vs-raws reads the stream as is
+        format!("core.raws.Source({})", py_path(&abs(file).unwrap()))
+    } else if args.source_filter == "lsmash" {
+        let pass1 = format!("core.lsmas.LWLibavSource({}, cachedir={}, prefer_hw=3", py_path(file), py_path(&args.input_directory));
         if format.is_some() {
             format!("{pass1}, format='{}')", format.unwrap())
         } else {
@@ -793,17 +1607,40 @@ fn get_source_string(file: &PathBuf, args: &Args, format: Option<String>) -> Str
         if !root.ends_with('/') {
             root.push('/');
         }
-        format!("bs.VideoSource(r'{}', cachepath=r'{}')", abs(&file).unwrap().display(), root)
+        format!("core.bs.VideoSource({}, cachepath={})", py_path(&abs(&file).unwrap()), py_path(&PathBuf::from(root)))
     } else {
-        format!("dgdecodenv.DGSource(r'{}')", file.display())
+        format!("core.dgdecodenv.DGSource({})", py_path(file))
+    }
+}
+
+// shared libraries in --vs-plugin-dir, loaded by the generated scripts and the metric core
+fn vs_plugins(args: &Args) -> Vec<PathBuf> {
+    if args.vs_plugin_dir.is_none() {
+        return Vec::new();
+    }
+    let extensions = ["so", "dll", "dylib"];
+    let dir = args.vs_plugin_dir.as_ref().unwrap().read_dir().expect("Failed to read VapourSynth plugin directory!");
+    let plugins = dir.map(|entry| abs(entry.unwrap().path()).unwrap()).filter(|path| path.extension().is_some_and(|e| e.to_str().is_some_and(|e| extensions.contains(&e))));
+    plugins.sorted().collect()
+}
+
+// mem is in GB, max_cache_size is in MB
+fn get_core_string(args: &Args, mem: u8) -> String {
+    let mut core_string = format!("core = vs.core\ncore.max_cache_size = {}\n", mem as u32 * 1024);
+    if let Some(threads) = args.vs_threads {
+        core_string = format!("{core_string}core.num_threads = {threads}\n");
+    }
+    for plugin in vs_plugins(args) {
+        core_string = format!("{core_string}core.std.LoadPlugin({})\n", py_path(&plugin));
     }
+    core_string
 }
 
 #[rustfmt::skip]
 fn sd_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
     let mut file = File::create(vpy_path).unwrap();
     let source_string = get_source_string(&vinfo[0].file, &args, Some(vinfo[0].pix_fmt(true)));
-    let contents = format!("import vapoursynth as vs\ncore = vs.core\nsrc = core.{source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\nsrc.set_output(0)");
+    let contents = format!("import vapoursynth as vs\n{}src = {source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\n{}src.set_output(0)", get_core_string(&args, args.sc_mem.unwrap_or(args.mem)), get_fps_string("src", &vinfo[0], &args));
     file.write_all(contents.as_bytes()).unwrap();
 }
 
@@ -817,12 +1654,65 @@ fn get_descale_dimensions(height: &Option<u16>, width: &Option<u16>) -> (u16, u1
     }
 }
 
+// placebo.Tonemap takes and returns 16 bit, the clip goes back to its own format afterwards
+fn get_tonemap_string(clip: &str, args: &Args) -> String {
+    let Some(csp) = args.tonemap_from else {
+        return String::new();
+    };
+    format!("hdr = core.resize.Point({clip}, format={clip}.format.replace(bits_per_sample=16, sample_type=vs.INTEGER))\nsdr = core.placebo.Tonemap(hdr, src_csp={csp}, dst_csp=0)\nsdr = core.resize.Bicubic(sdr, format={clip}.format.id, matrix_s=\"709\")\n{clip} = core.std.SetFrameProps(sdr, _Matrix=1, _Transfer=1, _Primaries=1)\n")
+}
+
+// source and target rate as the two sides of source/target, None when nothing has to be converted
+fn fps_conversion(video: &Probe, args: &Args) -> Option<(u64, u64)> {
+    let target = args.target_fps.as_ref()?;
+    let parse = |rate: &String| -> (u64, u64) { rate.split("/").map(|n| n.parse::<u64>().unwrap()).collect_tuple().unwrap() };
+    let ((source_num, source_den), (target_num, target_den)) = (parse(video.stream.avg_frame_rate.as_ref().unwrap()), parse(target));
+    let (source, target) = (source_num * target_den, source_den * target_num);
+    (source != target).then_some((source, target))
+}
+
+// inserted right after the source so that VFM still sees the original fields
+fn get_fps_string(clip: &str, video: &Probe, args: &Args) -> String {
+    let Some((source, target)) = fps_conversion(video, args) else {
+        return String::new();
+    };
+    if source * 4 == target * 5 {
+        format!("{clip} = core.vivtc.VFM({clip}, 1, mode=3)\n{clip} = core.vivtc.VDecimate({clip}, 5)\n")
+    } else {
+        let (numerator, denominator) = args.target_fps.as_ref().unwrap().split_once("/").unwrap();
+        format!("from fractions import Fraction\nfrom vstools import change_fps\n{clip} = change_fps({clip}, Fraction({numerator}, {denominator}))\n")
+    }
+}
+
+fn check_target_fps(video: &Probe, args: &Args) {
+    let Some((source, target)) = fps_conversion(video, args) else {
+        return;
+    };
+    let (from, to) = (video.stream.avg_frame_rate.as_ref().unwrap(), args.target_fps.as_ref().unwrap());
+    if source * 4 == target * 5 {
+        println_log!("Converting {} from {from} to {to} fps with VFM/VDecimate, this assumes the source is telecined", video.file.display());
+    } else if source % target == 0 || target % source == 0 {
+        println_log!("Converting {} from {from} to {to} fps by dropping or repeating frames", video.file.display());
+    } else {
+        eprintln_log!("WARNING: {} is {from} fps and {to} isn't a whole multiple of it, frames will be dropped or repeated unevenly and motion will judder!", video.file.display());
+    }
+}
+
+// vstools picks the dither itself, going down to 8-bit asks for error diffusion since banding shows the most there
+fn get_depth_string(clip: &str, args: &Args) -> String {
+    if args.output_depth == "8" {
+        format!("depth({clip}, 8, dither_type=DitherType.ERROR_DIFFUSION)")
+    } else {
+        format!("depth({clip}, {})", args.output_depth)
+    }
+}
+
 #[rustfmt::skip]
 fn create_vpy_script(vpy_path: &PathBuf, file_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
     let mut file = File::create(vpy_path).unwrap();
     let source_string = get_source_string(&vinfo[0].file, &args, Some(vinfo[0].pix_fmt(true)));
-    let mut imports = format!("import vapoursynth as vs\nfrom vstools import initialize_clip, depth\nfrom vsdeband import F3kdb, masked_deband\n");
-    let mut contents = format!("core = vs.core\ncore.max_cache_size = {}\nsrc = core.{source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\nsrc = initialize_clip(src)\n", args.mem as u32 * 1024);
+    let mut imports = format!("import vapoursynth as vs\nfrom vstools import initialize_clip, depth, DitherType\n");
+    let mut contents = format!("{}src = {source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\n{}src = initialize_clip(src)\n{}", get_core_string(&args, args.mem), get_fps_string("src", &vinfo[0], &args), get_tonemap_string("src", &args));
     if args.rescale {
         imports = format!("{imports}import lvsfunc as lvs\nimport vskernels as vsk\nfrom vodesfunc import RescaleBuilder\nfrom vsscale import ArtCNN\n");
         let (descale_height, descale_width) = get_descale_dimensions(&args.height, &args.width);
@@ -850,12 +1740,18 @@ fn create_vpy_script(vpy_path: &PathBuf, file_path: &PathBuf, args: &Args, vinfo
         imports = format!("{imports}from vsdehalo import fine_dehalo\n");
         contents = format!("{contents}src = fine_dehalo(src, planes=[0,1,2])\n");
     }
-    let deband_string: &'static str = if args.retinex {
-        "masked_deband(src, grain=0, rg_mode=0"
+    let deband_function: &'static str = if args.retinex {
+        "masked_deband"
     } else {
-        "F3kdb.deband(src"
+        "F3kdb.deband"
     };
-    contents = format!("{imports}{contents}deband = {deband_string}, thr={}, planes=[0,1,2])\ndown = depth(deband, 10)\ndown.set_output(0)\n# audio = core.bs.AudioSource(r'{}', cachepath=r'{}/')\n# start1 = round(1004*48*1001/30) # Values based on audio sample rate. Multiply video frame number by sample rate in kHz/original framerate\n# end1 = round(10893*48*1001/30)\n# start2 = round(11194*48*1001/30)\n# end2 = round(44161*48*1001/30)\n# a1 = audio[start1:end1]\n# a2 = audio[start2:end2]\n# audio=a1+a2\n# audio.set_output(1)", args.deband, file_path.display(), args.input_directory.display());
+    let mut output = "src";
+    if !args.no_deband {
+        imports = format!("{imports}from vsdeband import F3kdb, masked_deband\n");
+        contents = format!("{contents}deband = {deband_function}(src, {})\n", get_deband_string(&args));
+        output = "deband";
+    }
+    contents = format!("{imports}{contents}down = {}\ndown.set_output(0)\n# audio = core.bs.AudioSource({}, cachepath={})\n# start1 = round(1004*48*1001/30) # Values based on audio sample rate. Multiply video frame number by sample rate in kHz/original framerate\n# end1 = round(10893*48*1001/30)\n# start2 = round(11194*48*1001/30)\n# end2 = round(44161*48*1001/30)\n# a1 = audio[start1:end1]\n# a2 = audio[start2:end2]\n# audio=a1+a2\n# audio.set_output(1)", get_depth_string(output, &args), py_path(file_path), py_path(&args.input_directory.join("")));
     file.write_all(contents.as_bytes()).unwrap();
 }
 
@@ -863,10 +1759,40 @@ fn create_vpy_script(vpy_path: &PathBuf, file_path: &PathBuf, args: &Args, vinfo
 fn multi_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
     let mut vpy_file = File::create(vpy_path).unwrap();
     let source_string = get_source_string(&vinfo[0].file, &args, Some(vinfo[0].pix_fmt(true)));
-    let content = format!("import vapoursynth as vs\ncore = vs.core\nsrc = core.{source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\nsrc = src[::{}]\nsrc.set_output(0)\n", args.cycle);
+    let content = format!("import vapoursynth as vs\n{}src = {source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\n{}{}src = src[::{}]\nsrc.set_output(0)\n", get_core_string(&args, args.mem), get_fps_string("src", &vinfo[0], &args), get_tonemap_string("src", &args), args.cycle);
+    vpy_file.write_all(content.as_bytes()).unwrap();
+}
+
+// luma of the source against the same nl_means the filter chain uses, on every cycle-th frame like the probes
+#[rustfmt::skip]
+fn grain_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
+    let mut vpy_file = File::create(vpy_path).unwrap();
+    let source_string = get_source_string(&vinfo[0].file, &args, None);
+    let content = format!("import vapoursynth as vs\nfrom vstools import initialize_clip\nfrom vsdenoise import nl_means\n{}src = {source_string}\n{}src = initialize_clip(src)\n{}src = src[::{}]\nnlm = nl_means(src, strength={}, tr=2, sr=[3,2,2], planes=[0])\nstats = core.std.PlaneStats(src, nlm, plane=0)\nstats.set_output(0)\n", get_core_string(&args, args.mem), get_fps_string("src", &vinfo[0], &args), get_tonemap_string("src", &args), args.cycle, args.denoise);
     vpy_file.write_all(content.as_bytes()).unwrap();
 }
 
+// turns the measured grain into av1an's photon noise strength per scene, between none and the full --photon-noise
+fn adaptive_grain(scenes_over: &PathBuf, levels: &BTreeMap<usize, f64>, args: &Args) {
+    let [low, high] = args.adaptive_grain.unwrap();
+    let full = args.native_photon_noise().unwrap() as f64;
+    let mut scenes_info: ScenesInfo = serde_json::from_reader(File::open(scenes_over).unwrap()).unwrap();
+    for scene in &mut scenes_info.scenes {
+        let scene_levels: Vec<f64> = levels.range(scene.start_frame as usize..scene.end_frame as usize).map(|(_, level)| *level).collect();
+        // scenes shorter than --cycle weren't measured, they get the grain of the whole episode
+        let level = if scene_levels.is_empty() {
+            levels.values().sum::<f64>() / levels.len() as f64
+        } else {
+            scene_levels.iter().sum::<f64>() / scene_levels.len() as f64
+        };
+        let strength = (full * ((level - low) / (high - low)).clamp(0., 1.)).round() as u16;
+        if let Some(overrides) = scene.zone_overrides.as_mut() {
+            overrides.photon_noise = (strength > 0).then_some(strength);
+        }
+    }
+    serde_json::to_writer(File::create(scenes_over).unwrap(), &scenes_info).unwrap();
+}
+
 #[rustfmt::skip]
 fn denoise_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
     let mut vpy_file = File::create(vpy_path).unwrap();
@@ -875,7 +1801,7 @@ fn denoise_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
     if args.ref_calc {
         denoise_string = format!("{denoise_string}, ref=MVTools.denoise(src, **MVToolsPresets.FAST)");
     }
-    let content = format!("import vapoursynth as vs\nfrom vstools import initialize_clip, depth\nfrom vsdenoise import nl_means, MVTools, MVToolsPresets\ncore = vs.core\ncore.max_cache_size = {}\nsrc = core.{source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\nsrc = initialize_clip(src)\nnlm = nl_means(src, {denoise_string}) # smaller window size for chroma subsampling\ndown = depth(nlm, 10)\ndown.set_output(0)\n", args.mem as u32 * 1024);
+    let content = format!("import vapoursynth as vs\nfrom vstools import initialize_clip, depth, DitherType\nfrom vsdenoise import nl_means, MVTools, MVToolsPresets\n{}src = {source_string}\n# clip1 = src[1004:10893]\n# clip2 = src[11194:44161]\n# src = clip1+clip2\n# src = core.vivtc.VFM(src, 1, mode=3) # 60i to 30p\n# src = core.vivtc.VDecimate(src, 5) # 30p to 24p\n{}src = initialize_clip(src)\n{}nlm = nl_means(src, {denoise_string}) # smaller window size for chroma subsampling\ndown = {}\ndown.set_output(0)\n", get_core_string(&args, args.mem), get_fps_string("src", &vinfo[0], &args), get_tonemap_string("src", &args), get_depth_string("nlm", &args));
     vpy_file.write_all(content.as_bytes()).unwrap();
 }
 
@@ -883,7 +1809,7 @@ fn denoise_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
 fn merge_script(vpy_path: &PathBuf, args: &Args, vinfo: &Vec<Probe>) {
     let mut vpy_file = File::create(vpy_path).unwrap();
     let (source1_string, source2_string) = (get_source_string(&vinfo[0].file, &args, None), get_source_string(&vinfo[1].file, &args, None));
-    let content = format!("import vapoursynth as vs\nfrom vstools import initialize_clip, depth\nfrom vsdenoise import frequency_merge\nfrom vsrgtools import box_blur\ncore = vs.core\nsrc1 = core.{source1_string}\nsrc1 = initialize_clip(src1)\nsrc2 = core.{source2_string}\nsrc2 = initialize_clip(src2)\n# clip1 = src1[1004:10893]\n# clip2 = src1[11194:44161]\n# src1 = clip1+clip2\n# src1 = core.vivtc.VFM(src1, 1, mode=3) # 60i to 30p\n# src1 = core.vivtc.VDecimate(src1, 5) # 30p to 24p\noffset = {} # from get_info\nframerate = src1.fps\n# Calculate the frame offset\noffset_frames = int(offset * framerate / -1000)\n# Conditional slicing based on the offset value\nif offset_frames >= 0:\nsrc2 = src2[offset_frames:]\nelse:\nsrc1 = src1[abs(offset_frames):]\nsrcs = [src1, src2]\nlehmer = frequency_merge(srcs, lowpass = lambda i: box_blur(i, passes=3))\ndown = depth(lehmer, 10)\ndown.set_output(0)\n", vinfo[1].offset);
+    let content = format!("import vapoursynth as vs\nfrom vstools import initialize_clip, depth, DitherType\nfrom vsdenoise import frequency_merge\nfrom vsrgtools import box_blur\n{}src1 = {source1_string}\nsrc1 = initialize_clip(src1)\n{}src2 = {source2_string}\nsrc2 = initialize_clip(src2)\n{}# clip1 = src1[1004:10893]\n# clip2 = src1[11194:44161]\n# src1 = clip1+clip2\n# src1 = core.vivtc.VFM(src1, 1, mode=3) # 60i to 30p\n# src1 = core.vivtc.VDecimate(src1, 5) # 30p to 24p\noffset = {} # from get_info\nframerate = {}\n# Calculate the frame offset\noffset_frames = int(offset * framerate / -1000)\n# Conditional slicing based on the offset value\nif offset_frames >= 0:\nsrc2 = src2[offset_frames:]\nelse:\nsrc1 = src1[abs(offset_frames):]\n{}{}srcs = [src1, src2]\nlehmer = frequency_merge(srcs, lowpass = {})\ndown = {}\ndown.set_output(0)\n", get_core_string(&args, args.mem), get_tonemap_string("src1", &args), get_tonemap_string("src2", &args), vinfo[1].offset, vinfo[0].fps(), get_fps_string("src1", &vinfo[0], &args), get_fps_string("src2", &vinfo[1], &args), get_lowpass_string(&args), get_depth_string("lehmer", &args));
     vpy_file.write_all(content.as_bytes()).unwrap();
 }
 
@@ -892,75 +1818,225 @@ fn scene_detection(vpy_path: &PathBuf, encode: &PathBuf, scenes: &PathBuf, temp:
     let (cr, matrix, transfer, primaries) = vinfo[0].color_data(false);
     let (quantizer, speed) = (args.quantizer, args.speed);
     Command::new(get_binary("av1an")).args([
-        "-i", vpy_path.to_str().unwrap(),
-        "-o", encode.to_str().unwrap(), "--temp", temp.to_str().unwrap(),
-        "--verbose", "-w", args.workers.to_string().as_str(),
-        "--scenes", scenes.to_str().unwrap(), "--sc-only", "--sc-pix-format", vinfo[0].pix_fmt(false).as_str(), "--sc-downscale-height", "720",
-        "-e", "svt-av1", "-v", format!("--crf {quantizer} --preset {speed} --tune 3 --sharpness 2 --variance-boost-strength 4 --variance-octile 4 --frame-luma-bias 100 --keyint 0 --enable-dlf 2 --enable-cdef 0 --enable-restoration 0 --enable-tf 0 --color-range {cr} --matrix-coefficients {matrix} --transfer-characteristics {transfer} --color-primaries {primaries}").as_str(),
-        "-m", args.source_filter.as_str(), "-c", "mkvmerge", "--pix-format", args.pixel_format.as_str()
-    ]).spawn().unwrap().wait().unwrap();
+        "-i", path_str(&vpy_path).as_str(),
+        "-o", path_str(&encode).as_str(), "--temp", path_str(&temp).as_str(),
+        "--verbose", "-w", get_workers(&args, &vinfo).to_string().as_str(),
+        "--scenes", path_str(&scenes).as_str(), "--sc-only", "--sc-pix-format", vinfo[0].pix_fmt(false).as_str(), "--sc-downscale-height", "720",
+        "-e", "svt-av1", "-v", format!("--crf {quantizer} --preset {speed} {} --color-range {cr} --matrix-coefficients {matrix} --transfer-characteristics {transfer} --color-primaries {primaries}", svt_params(args).join(" ")).as_str(),
+        "-m", args.chunk_method(), "-c", args.concat.as_str(), "--pix-format", args.pixel_format().as_str()
+    ]).args(keyint_split(args)).args(args.av1an_args()).logged().spawn().unwrap().wait().unwrap();
 }
 
-fn quantizer_range(range: Option<String>, encoder: String) -> [f32; 2] {
-    if range.is_none() {
-        if encoder == "rav1e" {
-            [40.0, 160.0]
-        } else {
-            [25.0, 55.0]
-        }
-    } else {
-        serde_json::from_str::<[f32; 2]>(range.unwrap().as_str())
-            .expect("Failed to parse quantizer range!")
+// every chunk starts with a keyframe, so with --keyint the "scenes" are even pieces of at most that many frames
+fn keyint_split(args: &Args) -> Vec<String> {
+    if args.keyint == 0 {
+        return Vec::new();
+    }
+    vec_into!["--split-method", "none", "--extra-split", args.keyint.to_string()]
+}
+
+// maps the scenes through the src[::cycle] decimation of multi_script instead of detecting them again
+fn skip_scenes(scenes: &PathBuf, scenes_skip: &PathBuf, args: &Args) {
+    let cycle = args.cycle as u32;
+    let scenes_read = File::open(scenes).unwrap();
+    let mut skip_info: ScenesInfo = serde_json::from_reader(scenes_read).unwrap();
+    for scene in &mut skip_info.scenes {
+        scene.start_frame = scene.start_frame.div_ceil(cycle);
+        scene.end_frame = scene.end_frame.div_ceil(cycle);
+    }
+    // scenes shorter than the cycle can end up without any frames
+    skip_info.scenes.retain(|scene| scene.end_frame > scene.start_frame);
+    skip_info.frames = skip_info.frames.div_ceil(cycle);
+    let writer = File::create(scenes_skip).unwrap();
+    serde_json::to_writer(writer, &skip_info).unwrap();
+}
+
+// the default ladder keeps its old names so earlier probes are resumed
+fn probe_suffix(modifier: i8) -> String {
+    match modifier {
+        2 => "_lowest.mkv".to_string(),
+        1 => "_low.mkv".to_string(),
+        -1 => "_high.mkv".to_string(),
+        -2 => "_highest.mkv".to_string(),
+        _ => format!("_q{modifier:+}.mkv"),
     }
 }
 
 #[rustfmt::skip]
 fn calculate_quantizer(args: &Args, modifier: i8) -> f32 {
     let part1: f32 = args.quantizer + args.quantizer_calc * modifier as f32;
-    let range = quantizer_range(args.quantizer_range.clone(), args.encoder.clone());
+    let range = args.quantizer_range();
     part1.clamp(range[0], range[1])
 }
 
+// probes score every cycle-th frame (src[::cycle], frame n maps back to n * cycle), so any whole cycle works
+fn probe_cycle(video: &Probe, probe_frames: u32, fallback: u8) -> u8 {
+    let frames = (video.duration() * video.fps()).round() as u32;
+    if frames == 0 {
+        eprintln_log!("WARNING: Couldn't determine the frame count of {}, using a cycle of {fallback}", video.file.display());
+        return fallback;
+    }
+    let cycle = (frames / probe_frames).clamp(1, u8::MAX as u32) as u8;
+    println_log!("Scoring every {cycle} frames for about {probe_frames} frames per probe");
+    cycle
+}
+
+// rough av1an worker footprint: encoder memory scaled from ~1.5 GB at 1080p plus the vspipe cache (--mem)
+fn get_workers(args: &Args, vinfo: &Vec<Probe>) -> u8 {
+    let cores = available_parallelism().map(|c| c.get()).unwrap_or(1).min(u8::MAX as usize) as u8;
+    if args.workers != "auto" {
+        return args.workers.parse().unwrap();
+    }
+    let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    let total_kb = meminfo.lines().find(|l| l.starts_with("MemTotal:")).and_then(|l| l.split_whitespace().nth(1)).and_then(|kb| kb.parse::<f64>().ok());
+    if total_kb.is_none() || vinfo[0].resolution() == 0 {
+        eprintln_log!("WARNING: Couldn't detect system memory or source resolution, using {cores} workers");
+        return cores;
+    }
+    let total_gb = total_kb.unwrap() / 1024f64 / 1024f64;
+    let per_worker = vinfo[0].resolution() as f64 / (1920f64 * 1080f64) * 1.5 + args.mem as f64;
+    // leave some room for the OS and grav1an itself
+    let workers = ((total_gb * 0.8 / per_worker) as u8).clamp(1, cores);
+    println_log!("Using {workers} workers for {}x{}", vinfo[0].stream.width.unwrap(), vinfo[0].stream.height.unwrap());
+    workers
+}
+
 fn temp_path(file_path: &PathBuf, ext: &str) -> PathBuf {
     let base = file_path.file_stem().unwrap();
     let parent = file_path.parent().unwrap();
-    parent.join(format!("{}{}", base.to_str().unwrap(), ext))
+    parent.join(format!("{}{}", path_str(&base), ext))
+}
+
+// Only removes the exact av1an temp directory given, other episodes' temps are left alone
+fn cleanup_temp(temp: &PathBuf, args: &Args) {
+    if !args.cleanup_on_success || temp.try_exists().is_ok_and(|b| b == false) {
+        return;
+    }
+    if let Err(e) = fs::remove_dir_all(temp) {
+        eprintln_log!("WARNING: Failed to remove temp directory {}: {e}", temp.display());
+    }
 }
 
 #[rustfmt::skip]
-fn encode_file(scene_detect: &PathBuf, script: &PathBuf, encode: &PathBuf, temp: &PathBuf, scenes: &PathBuf, speed: Option<u8>, quantizer: Option<f32>, encoder: Option<&str>, keep: bool, args: &Args, vinfo: &Vec<Probe>) {
+fn encode_file(scene_detect: &PathBuf, script: &PathBuf, encode: &PathBuf, temp: &PathBuf, scenes: &PathBuf, speed: Option<u8>, quantizer: Option<f32>, encoder: Option<&str>, keep: bool, photon_noise: Option<u16>, args: &Args, vinfo: &Vec<Probe>) {
     let input = if args.no_filter {
         scene_detect
     } else {
         script
     };
     let params = get_encoder_params(&args, &vinfo, speed, quantizer, encoder, false);
-    let (input, encode, temp, workers, scenes, pf) = (input.to_str().unwrap(), encode.to_str().unwrap(), temp.to_str().unwrap(), args.workers.to_string(), scenes.to_str().unwrap(), vinfo[0].pix_fmt(false));
+    let (input, encode, temp, workers, scenes, pf) = (path_str(&input), path_str(&encode), path_str(&temp), get_workers(&args, &vinfo).to_string(), path_str(&scenes), vinfo[0].pix_fmt(false));
+    let pixel_format = args.pixel_format();
+    let photon_noise = photon_noise.map(|strength| strength.to_string());
+    let chroma_noise = args.grain_chroma;
+    let av1an_args = args.av1an_args();
     let mut args = vec![
-        "-i", input,
-        "-o", encode, "--temp", temp,
+        "-i", input.as_str(),
+        "-o", encode.as_str(), "--temp", temp.as_str(),
         "--verbose", "--resume", "-w", workers.as_str(),
-        "--scenes", scenes, "--sc-pix-format", pf.as_str(), "--sc-downscale-height", "360",
+        "--scenes", scenes.as_str(), "--sc-pix-format", pf.as_str(), "--sc-downscale-height", "360",
         "-e", encoder.unwrap_or(args.encoder.as_str()), "-v", params.as_str(),
-        "-m", args.source_filter.as_str(), "-c", "mkvmerge", "--pix-format", args.pixel_format.as_str()
+        "-m", args.chunk_method(), "-c", args.concat.as_str(), "--pix-format", pixel_format.as_str()
     ];
     if keep {
         args.push("--keep");
     }
-    Command::new(get_binary("av1an")).args(args).spawn().unwrap().wait().unwrap();
-    if PathBuf::from(encode).try_exists().is_ok_and(|b| b == false) {
+    if let Some(strength) = &photon_noise {
+        args.extend(["--photon-noise", strength.as_str()]);
+        if chroma_noise {
+            args.push("--chroma-noise");
+        }
+    }
+    args.extend(av1an_args.iter().map(|a| a.as_str()));
+    let status = Command::new(get_binary("av1an")).args(args).logged().spawn().unwrap().wait().unwrap();
+    if !status.success() {
+        interrupt::discard(&[&PathBuf::from(&encode)]);
+    }
+    if PathBuf::from(&encode).try_exists().is_ok_and(|b| b == false) {
         panic!("Av1an failed to encode file!");
     }
 }
 
-fn get_ssimulacra2(src: &PathBuf, distorted: &PathBuf, scenes_info: &mut ScenesInfo, quantizer: f32, args: &Args, cr: &String, matrix: &String, transfer: &String, primaries: &String) {
-    let cache = temp_path(distorted, ".ssimu2");
+// centered quantiles of the final Q/crf so both easy and hard scenes are picked, without overrides they're spread over time
+fn pick_sample_scenes(scenes: &Vec<Scene>, count: usize) -> Vec<Scene> {
+    let mut ranked = scenes.clone();
+    ranked.sort_by(|a, b| a.final_quantizer.unwrap_or(0.).total_cmp(&b.final_quantizer.unwrap_or(0.)));
+    let count = count.min(ranked.len());
+    let mut picked: Vec<Scene> = (0..count).map(|i| ranked[ranked.len() * (2 * i + 1) / (2 * count)].clone()).collect();
+    picked.sort_by_key(|scene| scene.start_frame);
+    picked
+}
+
+// encodes only the picked scenes of the filtered source back to back with their zone overrides, returns the encode and its frame count
+#[rustfmt::skip]
+fn encode_picked_scenes(input: &PathBuf, scenes_file: &PathBuf, file_path: &PathBuf, name: &str, count: u32, args: &Args, vinfo: &Vec<Probe>) -> (PathBuf, u32) {
+    let (sample_script, sample_scenes, sample_encode, temp) = (temp_path(file_path, format!("_{name}.vpy").as_str()), temp_path(file_path, format!("_{name}.json").as_str()), temp_path(file_path, format!("_{name}_enc.mkv").as_str()), av1an_temp(temp_path(file_path, format!("_{name}").as_str())));
+    let scenes_info: ScenesInfo = serde_json::from_reader(File::open(scenes_file).unwrap()).unwrap();
+    let mut picked = pick_sample_scenes(&scenes_info.scenes, count as usize);
+    let ranges = picked.iter().map(|scene| format!("src[{}:{}]", scene.start_frame, scene.end_frame)).join(" + ");
+    let mut frames = 0;
+    for scene in &mut picked {
+        let length = scene.end_frame - scene.start_frame;
+        (scene.start_frame, scene.end_frame) = (frames, frames + length);
+        frames += length;
+    }
+    let content = format!("import vapoursynth as vs\n{}src = {}\nsrc = {ranges}\nsrc.set_output(0)\n", get_core_string(&args, args.mem), get_source_string(input, &args, None));
+    File::create(&sample_script).unwrap().write_all(content.as_bytes()).unwrap();
+    serde_json::to_writer(File::create(&sample_scenes).unwrap(), &ScenesInfo { scenes: picked.clone(), frames }).unwrap();
+    // the picks change with --sample-scenes, so nothing of an earlier sample is resumed
+    let _ = fs::remove_dir_all(&temp);
+    let _ = fs::remove_file(&sample_encode);
+    encode_file(&sample_script, &sample_script, &sample_encode, &temp, &sample_scenes, Some(args.speed), Some(args.quantizer), None, true, args.native_photon_noise(), &args, &vinfo);
+    cleanup_temp(&temp, &args);
+    (sample_encode, frames)
+}
+
+// the picked scenes with their grain, for checking filters, grain and zone overrides
+fn encode_sample(input: &PathBuf, scenes_file: &PathBuf, file_path: &PathBuf, sample: &PathBuf, count: u32, args: &Args, vinfo: &Vec<Probe>) {
+    let (sample_encode, _) = encode_picked_scenes(input, scenes_file, file_path, "sample", count, args, vinfo);
+    if args.no_grain || args.native_photon_noise().is_some() {
+        fs::copy(&sample_encode, sample).unwrap();
+    } else {
+        if args.diff_grain {
+            eprintln_log!("WARNING: --diff-grain needs the whole episode, the sample gets --photon-noise {} instead", args.photon_noise);
+        }
+        let _ = fs::remove_file(sample);
+        add_grain_table(&sample_encode, sample, args.photon_noise, args);
+    }
+    println_log!("Sample of {count} scenes done at {}", sample.display());
+}
+
+// extrapolated from the encoding speed and bitrate of a few scenes, without the probes target quality can land well off
+fn estimate_encode(input: &PathBuf, scenes_file: &PathBuf, file_path: &PathBuf, count: u32, args: &Args, vinfo: &Vec<Probe>) -> (f64, u64) {
+    let total_frames = scene_frames(scenes_file).expect("Failed to read the detected scenes!");
+    let started = std::time::Instant::now();
+    let (sample_encode, frames) = encode_picked_scenes(input, scenes_file, file_path, "estimate", count, args, vinfo);
+    let seconds = started.elapsed().as_secs_f64();
+    let scale = total_frames as f64 / frames as f64;
+    let (time, size) = (seconds * scale, (sample_encode.metadata().unwrap().len() as f64 * scale) as u64);
+    println_log!(
+        "Estimate for {}: {} and {:.0} MB ({frames} of {total_frames} frames encoded at {:.2} fps)",
+        file_path.display(),
+        format_seconds(time),
+        size as f64 / 1e6,
+        frames as f64 / seconds
+    );
+    (time, size)
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let minutes = (seconds / 60.).round() as u64;
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+fn get_ssimulacra2(src: &PathBuf, distorted: &PathBuf, quantizer: f32, args: &Args, cr: &String, matrix: &String, transfer: &String, primaries: &String) -> BTreeMap<usize, f64> {
+    let cache = cache_path(temp_path(distorted, ".ssimu2"));
     let results = if cache.try_exists().is_ok_and(|b| b == false) {
-        println!("Calculating SSIMULACRA 2 Scores for Q{quantizer}");
+        println_log!("Calculating SSIMULACRA 2 Scores for Q{quantizer}");
         let hi = if args.ssimu2_algo == "vszip" {
-            get_vs_ssimu2(src, distorted, args.cycle, &args.source_filter)
+            get_vs_ssimu2(src, distorted, &args)
         } else {
-            get_ssimu2(src, distorted, args.cycle, cr.clone(), matrix.clone(), transfer.clone(), primaries.clone())
+            get_ssimu2(src, distorted, args.cycle, &args.source_filter, args.metric_sample, args.metric_stride, cr.clone(), matrix.clone(), transfer.clone(), primaries.clone(), args.verbose)
         };
         let file = File::create(cache).unwrap();
         serde_json::to_writer(file, &hi).expect("Failed to cache SSIMULCRA2 scores!");
@@ -971,6 +2047,11 @@ fn get_ssimulacra2(src: &PathBuf, distorted: &PathBuf, scenes_info: &mut ScenesI
         file.read_to_string(&mut contents).unwrap();
         serde_json::from_str(contents.as_str()).unwrap()
     };
+    results
+}
+
+// kept apart from get_ssimulacra2 so probes can be scored concurrently and merged afterwards
+fn add_quantizer_scores(scenes_info: &mut ScenesInfo, results: BTreeMap<usize, f64>, quantizer: f32) {
     let filtered: BTreeMap<usize, f64> = results.into_iter().filter(|e| e.1 > 0f64).collect();
     for scene in scenes_info.scenes.iter_mut() {
         let (start, end) = (scene.start_frame, scene.end_frame);
@@ -989,6 +2070,113 @@ fn get_ssimulacra2(src: &PathBuf, distorted: &PathBuf, scenes_info: &mut ScenesI
     }
 }
 
+// curve of the whole episode, the mean of every scene's target metric at each probe
+fn episode_fit(scenes_info: &ScenesInfo, args: &Args) -> Vec<f64> {
+    let mut by_quantizer: BTreeMap<usize, Vec<f64>> = BTreeMap::new();
+    for scores in scenes_info.scenes.iter().filter_map(|scene| scene.quantizer_scores.as_ref()) {
+        for (quantizer, data) in scores {
+            by_quantizer.entry(*quantizer).or_default().push(data.get(&args.target_metric));
+        }
+    }
+    let quantizers: Vec<f64> = by_quantizer.keys().map(|q| *q as f64).collect();
+    let means: Vec<f64> = by_quantizer.values().map(|values| values.iter().sum::<f64>() / values.len() as f64).collect();
+    polyfit(&means, &quantizers, 3).unwrap()
+}
+
+fn source_group(file: &PathBuf) -> Option<String> {
+    let name = path_str(&file.file_name().unwrap());
+    let captures = Regex::new(r"^\[([^\]]+)\]").unwrap().captures(&name).ok()??;
+    Some(captures[1].to_string())
+}
+
+// encodes and scores one target quality probe, av1an's temp lives next to the probe
+#[rustfmt::skip]
+fn probe_encode(skip_frames: &PathBuf, scenes_skip: &PathBuf, probe: &PathBuf, quantizer: f32, speed: u8, args: &Args, vinfo: &Vec<Probe>, color: (&String, &String, &String, &String)) -> BTreeMap<usize, f64> {
+    let (cr, matrix, transfer, primaries) = color;
+    let probe_temp = av1an_temp(temp_path(probe, ""));
+    if !is_valid_intermediate(probe, scene_frames(scenes_skip)) {
+        // scores of the probe that was there are stale now
+        let _ = fs::remove_file(cache_path(temp_path(probe, ".ssimu2")));
+        encode_file(skip_frames, skip_frames, probe, &probe_temp, scenes_skip, Some(speed), Some(quantizer), None, false, None, args, vinfo);
+    }
+    let scores = get_ssimulacra2(skip_frames, probe, quantizer, args, cr, matrix, transfer, primaries);
+    cleanup_temp(&probe_temp, args);
+    scores
+}
+
+// "420", "422" or "444" for planar yuv and gbr formats, None for gray and anything unknown
+fn chroma_subsampling(pix_fmt: &str) -> Option<&'static str> {
+    let pix_fmt = pix_fmt.replace("yuvj", "yuv");
+    if pix_fmt.starts_with("yuv444") || pix_fmt.starts_with("gbr") {
+        Some("444")
+    } else if pix_fmt.starts_with("yuv422") {
+        Some("422")
+    } else if pix_fmt.starts_with("yuv420") || pix_fmt == "nv12" || pix_fmt.starts_with("p010") {
+        Some("420")
+    } else {
+        None
+    }
+}
+
+// encoding to less chroma resolution than the source has loses it for good, --match-chroma keeps the source's
+fn check_chroma(video: &Probe, args: &mut Args) {
+    let source = chroma_subsampling(&video.pix_fmt(false));
+    if args.match_chroma {
+        args.output_chroma = source.map(String::from);
+    }
+    let output = chroma_subsampling(&args.pixel_format());
+    let (Some(source), Some(output)) = (source, output) else {
+        return;
+    };
+    // 420 < 422 < 444 as strings too
+    if output < source {
+        let message = format!("{} is {source} but is encoded as {output}, its chroma resolution is lost", video.file.display());
+        if args.strict {
+            panic!("{message}");
+        }
+        eprintln_log!("WARNING: {message}, use --match-chroma with rav1e or --pixel-format to keep it");
+    }
+}
+
+// PQ and HLG sources get tonemapped to BT.709 with --tonemap, so everything downstream sees SDR
+fn check_hdr(video: &mut Probe, args: &mut Args) {
+    args.tonemap_from = None;
+    if !args.tonemap {
+        return;
+    }
+    // placebo.Tonemap's src_csp: 1 is HDR10, 2 is HLG
+    args.tonemap_from = match video.stream.color_transfer.as_deref() {
+        Some("smpte2084") => Some(1),
+        Some("arib-std-b67") => Some(2),
+        _ => None,
+    };
+    if args.tonemap_from.is_none() {
+        eprintln_log!("WARNING: {} isn't HDR, it won't be tonemapped", video.file.display());
+        return;
+    }
+    video.stream.color_space = Some("bt709".to_string());
+    video.stream.color_transfer = Some("bt709".to_string());
+    video.stream.color_primaries = Some("bt709".to_string());
+}
+
+// --q-smooth, the mean of every scene's window of radius scenes on either side
+fn smooth_quantizers(raw: &[f32], radius: u8, q_range: [f32; 2]) -> Vec<f32> {
+    (0..raw.len()).map(|i| {
+        let window = &raw[i.saturating_sub(radius as usize)..(i + radius as usize + 1).min(raw.len())];
+        let mean = window.iter().sum::<f32>() / window.len() as f32;
+        mean.clamp(q_range[0], q_range[1])
+    }).collect()
+}
+
+// rav1e only takes integer quantizers, svt-av1 takes crf in quarter steps
+fn round_quantizer(q: f32, args: &Args) -> f32 {
+    if args.encoder == "rav1e" {
+        q.trunc()
+    } else {
+        (q * 4.).round() / 4.
+    }
+}
+
 fn zone_overrides(
     scenes_info: &mut ScenesInfo,
     scenes_path: &PathBuf,
@@ -998,55 +2186,98 @@ fn zone_overrides(
     matrix: &String,
     transfer: &String,
     primaries: &String,
+    shared: Option<&Vec<f64>>,
 ) {
     let mut quantizers: Vec<f64> = Vec::new();
-    let mut minus_sigma_values: Vec<f64> = Vec::new();
+    let mut target_values: Vec<f64> = Vec::new();
+    let mut p5_values: Vec<f64> = Vec::new();
     for scene in &mut scenes_info.scenes {
-        for (quantizer, data) in scene.quantizer_scores.as_ref().unwrap() {
+        let Some(quantizer_scores) = scene.quantizer_scores.as_ref() else {
+            continue;
+        };
+        let q_range = args.quantizer_range();
+        if let Some(shared) = shared {
+            // the scene's verification probe says how far it sits from the shared curve, the curve's shape is kept
+            let (quantizer, data) = quantizer_scores.iter().next().unwrap();
+            let polynomial = polynomial::Polynomial::new(shared.clone());
+            let offset = *quantizer as f64 - polynomial.eval(data.get(&args.target_metric));
+            scene.final_quantizer = Some(((polynomial.eval(args.target_quality as f64) + offset) as f32).clamp(q_range[0], q_range[1]));
+            continue;
+        }
+        for (quantizer, data) in quantizer_scores {
             quantizers.push(quantizer.clone() as f64);
-            minus_sigma_values.push(data.percentile_16th);
+            target_values.push(data.get(&args.target_metric));
+            p5_values.push(data.percentile_5th);
         }
-        let minus_sigma_corr = polyfit(&minus_sigma_values, &quantizers, 3).unwrap();
-        let q_range = quantizer_range(args.quantizer_range.clone(), args.encoder.clone());
-        let q = if !minus_sigma_corr.iter().all(|f| *f == 0.) {
-            let polynomial = polynomial::Polynomial::new(minus_sigma_corr);
+        let target_corr = polyfit(&target_values, &quantizers, 3).unwrap();
+        let mut q = if !target_corr.iter().all(|f| *f == 0.) {
+            let polynomial = polynomial::Polynomial::new(target_corr);
             (polynomial.eval(args.target_quality as f64) as f32).clamp(q_range[0], q_range[1])
         } else {
             q_range[1]
         };
-        if args.encoder == "rav1e" {
-            scene.final_quantizer = Some((q as i8) as f32);
-        } else {
-            scene.final_quantizer = Some((q * 4.).round() / 4.);
+        // worst frames of the scene also have to reach the floor, lower the q if the target alone doesn't get there
+        if let Some(min_frame_quality) = args.min_frame_quality {
+            let p5_corr = polyfit(&p5_values, &quantizers, 3).unwrap();
+            if !p5_corr.iter().all(|f| *f == 0.) {
+                let polynomial = polynomial::Polynomial::new(p5_corr);
+                let floor_q = (polynomial.eval(min_frame_quality as f64) as f32).clamp(q_range[0], q_range[1]);
+                q = q.min(floor_q);
+            }
         }
+        scene.final_quantizer = Some(q);
         quantizers.clear();
-        minus_sigma_values.clear();
+        target_values.clear();
+        p5_values.clear();
+    }
+    // --metric-sample can leave scenes without a scored frame, they take the Q/crf of the closest scored scene
+    let scored: Vec<(usize, f32)> = scenes_info.scenes.iter().enumerate().filter_map(|(i, s)| s.final_quantizer.map(|q| (i, q))).collect();
+    if scored.is_empty() {
+        panic!("No scene has a SSIMULACRA2 score, raise --metric-sample");
+    }
+    for (i, scene) in scenes_info.scenes.iter_mut().enumerate() {
+        if scene.final_quantizer.is_none() {
+            scene.final_quantizer = Some(scored.iter().min_by_key(|(j, _)| j.abs_diff(i)).unwrap().1);
+        }
+    }
+    if let Some(radius) = args.q_smooth {
+        let raw: Vec<f32> = scenes_info.scenes.iter().map(|s| s.final_quantizer.unwrap()).collect();
+        for (scene, q) in scenes_info.scenes.iter_mut().zip(smooth_quantizers(&raw, radius, args.quantizer_range())) {
+            scene.final_quantizer = Some(q);
+        }
+    }
+    // each scene is pulled toward the one before it so the quality doesn't jump between them
+    if let Some(delta) = args.max_scene_q_delta {
+        let mut previous: Option<f32> = None;
+        for scene in &mut scenes_info.scenes {
+            let q = scene.final_quantizer.unwrap();
+            let clamped = previous.map_or(q, |p| q.clamp(p - delta, p + delta));
+            scene.final_quantizer = Some(clamped);
+            previous = Some(clamped);
+        }
+    }
+    for scene in &mut scenes_info.scenes {
+        scene.final_quantizer = Some(round_quantizer(scene.final_quantizer.unwrap(), args));
     }
     let scenes_o_read = File::open(scenes_path).unwrap();
     let mut scenes_o: ScenesInfo = serde_json::from_reader(scenes_o_read).unwrap();
     for scene in scenes_info.scenes.clone() {
         let q_32 = scene.final_quantizer.unwrap();
-        let (q, speed, tiles) = (
-            q_32.to_string(),
-            args.speed.to_string(),
-            args.tiles.to_string(),
-        );
+        let (q, speed) = (q_32.to_string(), args.speed.to_string());
         for scene_o in &mut scenes_o.scenes {
             if scene_o.start_frame != scene.start_frame && scene_o.end_frame != scene.end_frame {
                 continue;
             }
+            scene_o.final_quantizer = Some(q_32);
             if args.encoder == "rav1e" {
-                let params: Vec<String> = vec_into![
-                    "--quantizer", q,
-                    "-s", speed,
-                    "--tiles", tiles,
-                    "--keyint", "0",
+                let params: Vec<String> = [rate_control_params(args, "rav1e", q), vec_into!["-s", speed], tile_params(args, "rav1e"), vec_into![
+                    "--keyint", args.keyint.to_string(),
                     "--no-scene-detection",
                     "--range", cr,
                     "--matrix", matrix,
                     "--transfers", transfer,
                     "--primaries", primaries
-                ];
+                ]].concat();
                 let parameters = if args.parameters.is_some() {
                     [params, args.parameters.as_ref().unwrap().split(" ").map(String::from).collect()].concat()
                 } else {
@@ -1056,30 +2287,19 @@ fn zone_overrides(
                     encoder: "rav1e".to_string(),
                     passes: 1,
                     video_params: parameters,
-                    photon_noise: None,
+                    photon_noise: args.native_photon_noise(),
+                    chroma_noise: args.grain_chroma,
                     extra_split_sec: 10,
                     min_scene_len: 24,
                 });
                 break;
             } else {
-                let params: Vec<String> = vec_into![
-                    "--crf", q,
-                    "--preset", speed,
-                    "--tune", "3",
-                    "--sharpness", "2",
-                    "--variance-boost-strength", "4",
-                    "--variance-octile", "4",
-                    "--frame-luma-bias", "100",
-                    "--keyint", "0",
-                    "--enable-dlf", "2",
-                    "--enable-cdef", "0",
-                    "--enable-restoration", "0",
-                    "--enable-tf", "0",
+                let params: Vec<String> = [rate_control_params(args, "svt-av1", q), vec_into!["--preset", speed], tile_params(args, "svt-av1"), svt_params(args), vec_into![
                     "--color-range", cr,
                     "--matrix-coefficients", matrix,
                     "--transfer-characteristics", transfer,
                     "--color-primaries", primaries
-                ];
+                ]].concat();
                 let parameters = if args.parameters.is_some() {
                     [params, args.parameters.as_ref().unwrap().split(' ').map(String::from).collect()].concat()
                 } else {
@@ -1089,7 +2309,8 @@ fn zone_overrides(
                     encoder: "svt_av1".to_string(),
                     passes: 1,
                     video_params: parameters,
-                    photon_noise: None,
+                    photon_noise: args.native_photon_noise(),
+                    chroma_noise: args.grain_chroma,
                     extra_split_sec: 10,
                     min_scene_len: 24,
                 });
@@ -1112,14 +2333,10 @@ fn validate_overrides(scenes_path: &PathBuf, args: &Args) {
             continue
         }
         let overrides = scene.zone_overrides.as_mut().unwrap();
-        if overrides.encoder == "rav1e" {
-            if overrides.video_params.len() == 17 { // number of arguments by default
-                overrides.video_params = [overrides.video_params.clone(), args.parameters.as_ref().unwrap().split(" ").map(String::from).collect()].concat();
-            }
-        } else {
-            if overrides.video_params.len() == 32 { // number of arguments by default
-                overrides.video_params = [overrides.video_params.clone(), args.parameters.as_ref().unwrap().split(" ").map(String::from).collect()].concat();
-            }
+        let parameters: Vec<String> = args.parameters.as_ref().unwrap().split(' ').map(String::from).collect();
+        // the parameter count varies with tiling and rate control, so check for the parameters themselves
+        if !overrides.video_params.ends_with(&parameters) {
+            overrides.video_params = [overrides.video_params.clone(), parameters].concat();
         }
     }
     let writer = File::create(scenes_path).unwrap();
@@ -1127,27 +2344,39 @@ fn validate_overrides(scenes_path: &PathBuf, args: &Args) {
 }
 
 #[rustfmt::skip]
-fn add_grain_table(encode: &PathBuf, grained: &PathBuf, photon_noise: u16) {
+fn add_grain_table(encode: &PathBuf, grained: &PathBuf, photon_noise: u16, args: &Args) {
     Command::new(get_binary("grav1synth"))
         .args([
-            "generate", encode.to_str().unwrap(),
-            "-o", grained.to_str().unwrap(),
+            "generate", path_str(&encode).as_str(),
+            "-o", path_str(&grained).as_str(),
             "--iso", photon_noise.to_string().as_str(),
         ])
-        .spawn().unwrap().wait().unwrap();
+        .args(args.grain_chroma.then_some("--chroma"))
+        .args(args.grain_params())
+        .logged().spawn().unwrap().wait().unwrap();
     if grained.try_exists().is_ok_and(|b| b==false) {
         panic!("Failed to create grain table!");
     }
 }
 
+// av1an's chunk extension for the diff grain reference encoders
+fn chunk_extension(encoder: &str) -> &'static str {
+    if encoder == "x265" {
+        "hevc"
+    } else {
+        "mkv"
+    }
+}
+
 fn grain_chunks(
     grainy_dir: &PathBuf,
     cleaned_dir: &PathBuf,
     encode_dir: &PathBuf,
     grained_dir: &PathBuf,
     chunk: &String,
+    grainy_ext: &str,
 ) {
-    let grainy = abs(grainy_dir.join(format!("{chunk}.mkv"))).unwrap();
+    let grainy = abs(grainy_dir.join(format!("{chunk}.{grainy_ext}"))).unwrap();
     let cleaned = abs(cleaned_dir.join(format!("{chunk}.ivf"))).unwrap();
     let gtable = abs(grainy_dir.join(format!("{chunk}_table.txt"))).unwrap();
     let encode = abs(encode_dir.join(format!("{chunk}.ivf"))).unwrap();
@@ -1155,9 +2384,9 @@ fn grain_chunks(
     if gtable.try_exists().is_ok_and(|b| b == false) {
         Command::new(get_binary("grav1synth"))
             .args([
-                "diff", grainy.to_str().unwrap(), cleaned.to_str().unwrap(),
-                "-o", gtable.to_str().unwrap(),
-            ]).spawn().unwrap().wait().unwrap();
+                "diff", path_str(&grainy).as_str(), path_str(&cleaned).as_str(),
+                "-o", path_str(&gtable).as_str(),
+            ]).logged().spawn().unwrap().wait().unwrap();
         if gtable.try_exists().is_ok_and(|b| b==false) {
             panic!("Failed to create grain table!");
         }
@@ -1165,10 +2394,10 @@ fn grain_chunks(
     if grained.try_exists().is_ok_and(|b| b == false) {
         Command::new(get_binary("grav1synth"))
             .args([
-                "apply", encode.to_str().unwrap(),
-                "-o", grained.to_str().unwrap(),
-                "-g", gtable.to_str().unwrap(),
-            ]).spawn().unwrap().wait().unwrap();
+                "apply", path_str(&encode).as_str(),
+                "-o", path_str(&grained).as_str(),
+                "-g", path_str(&gtable).as_str(),
+            ]).logged().spawn().unwrap().wait().unwrap();
         if grained.try_exists().is_ok_and(|b| b==false) {
             panic!("Failed to create grained video!");
         }
@@ -1180,6 +2409,7 @@ fn get_diff_grain(
     cleaned_temp: &PathBuf,
     temp: &PathBuf,
     grained: &PathBuf,
+    grainy_ext: &str,
 ) {
     let grainy_dir = grainy_temp.join("encode");
     let cleaned_dir = cleaned_temp.join("encode");
@@ -1193,42 +2423,125 @@ fn get_diff_grain(
         f.unwrap().path().file_stem().unwrap().to_string_lossy().to_string()
     });
     for chunk in matching_files {
-        grain_chunks(&grainy_dir, &cleaned_dir, &encode_dir, &grained_dir, &chunk);
+        grain_chunks(&grainy_dir, &cleaned_dir, &encode_dir, &grained_dir, &chunk, grainy_ext);
     }
     let input_files = Vec::from_iter(grained_dir.read_dir().unwrap().map(|f| abs(f.unwrap().path()).unwrap().to_string_lossy().to_string()));
     let mut vec_input: Vec<&str> = input_files.iter().map(|f| &**f).collect();
-    let mut args = vec!["mkvmerge", "-q", "-o", grained.to_str().unwrap(), "["];
+    let grained_str = path_str(&grained);
+    let mut args = vec!["mkvmerge", "-q", "-o", grained_str.as_str(), "["];
     args.append(&mut vec_input);
     args.append(&mut vec!["]"]);
     Command::new(get_binary("mkvmerge"))
         .args(args)
         .current_dir(&grained_dir)
-        .spawn().unwrap().wait().unwrap();
+        .logged().spawn().unwrap().wait().unwrap();
     if grained.try_exists().is_ok_and(|b| b==false) {
         panic!("mkvmerge failed to create grained video!");
     }
 }
 
-fn get_tags(tags_file: &PathBuf, encoder_options: Option<String>, args: &Args) {
-    let mut tags = format!("<Tags>\n");
-    if !args.single_pass {
-        tags = format!("{tags}  <Tag>\n    <Simple>\n      <Name>Target SSIMULACRA 2</Name>\n      <String>Mean: {}</String>\n    </Simple>\n  </Tag>\n", args.target_quality);
-    }
-    tags = format!("{tags}  <Tag>\n    <Simple>\n      <Name>Encoder settings</Name>\n      <String>{}: \"{}\"</String>\n    </Simple>\n  </Tag>\n", get_encoder_version(args.encoder.clone().as_str()).unwrap(), encoder_options.unwrap());
-    if !args.no_grain {
-        tags = format!("{tags}  <Tag>\n    <Simple>\n      <Name>Film grain synthesis settings</Name>\n      <String>grav1synth: {}</String>\n    </Simple>\n  </Tag>\n", get_grain_string(&args));
+// settings of an episode's encode, built once and rendered into the mkv tags, torrent comment, and nfo
+#[derive(Clone, Debug)]
+struct ReleaseInfo {
+    series: Option<String>,
+    raws: String,
+    target_quality: Option<String>,
+    bitrate: Option<u32>,
+    encoder_version: String,
+    encoder_build: String,
+    encoder_options: String,
+    grain: Option<String>,
+    filters: Option<String>,
+    rescale: Option<String>,
+}
+impl ReleaseInfo {
+    fn new(encoder_options: String, args: &Args) -> ReleaseInfo {
+        ReleaseInfo {
+            series: args.series_title.clone(),
+            raws: args.source_label(),
+            target_quality: (!args.single_pass).then(|| format!("{}: {}", args.target_metric_name(), args.target_quality)),
+            bitrate: args.bitrate,
+            encoder_version: get_encoder_version(args.encoder.as_str(), args).unwrap(),
+            encoder_build: get_encoder_build(args.encoder.as_str()).unwrap(),
+            encoder_options,
+            grain: (!args.no_grain).then(|| match args.native_photon_noise() {
+                Some(strength) if args.adaptive_grain.is_some() => format!("av1an: adaptive --photon-noise up to {strength}, thresholds {:?}{}", args.adaptive_grain.unwrap(), if args.grain_chroma { " --chroma-noise" } else { "" }),
+                Some(strength) => format!("av1an: --photon-noise {strength}{}", if args.grain_chroma { " --chroma-noise" } else { "" }),
+                None => format!("grav1synth: {}", get_grain_string(&args)),
+            }),
+            filters: (!args.no_filter).then(|| get_filter_string(&args)),
+            rescale: args.rescale.then(|| get_rescale_string(&args)),
+        }
     }
-    if !args.no_filter {
-        tags = format!("{tags}  <Tag>\n    <Simple>\n      <Name>Vapoursynth filters</Name>\n      <String>{}</String>\n    </Simple>\n  </Tag>\n", get_filter_string(&args));
+    fn lines(&self) -> Vec<(&'static str, String)> {
+        let mut lines = Vec::new();
+        if let Some(series) = &self.series {
+            lines.push(("Series", series.clone()));
+        }
+        lines.push(("Raws", self.raws.clone()));
+        if let Some(target_quality) = &self.target_quality {
+            lines.push(("Target SSIMULACRA 2", target_quality.clone()));
+        }
+        if let Some(bitrate) = self.bitrate {
+            lines.push(("Target average bitrate", format!("{bitrate} kbps")));
+        }
+        lines.push(("Encoder settings", format!("{}: \"{}\"", self.encoder_version, self.encoder_options)));
+        lines.push(("Encoder build", self.encoder_build.clone()));
+        if let Some(grain) = &self.grain {
+            lines.push(("Film grain synthesis settings", grain.clone()));
+        }
+        if let Some(filters) = &self.filters {
+            lines.push(("Vapoursynth filters", filters.clone()));
+        }
+        if let Some(rescale) = &self.rescale {
+            lines.push(("Rescale settings", rescale.clone()));
+        }
+        lines
     }
-    if args.rescale {
-        tags = format!("{tags}  <Tag>\n    <Simple>\n      <Name>Rescale settings</Name>\n      <String>{}</String>\n    </Simple>\n  </Tag>\n", get_rescale_string(&args));
+}
+
+fn get_tags(tags_file: &PathBuf, release_info: &ReleaseInfo) {
+    let mut tags = format!("<Tags>\n");
+    for (name, value) in release_info.lines() {
+        tags = format!("{tags}  <Tag>\n    <Simple>\n      <Name>{name}</Name>\n      <String>{value}</String>\n    </Simple>\n  </Tag>\n");
     }
     tags = format!("{tags}</Tags>");
     let mut file = File::create(tags_file).unwrap();
     file.write_all(tags.as_bytes()).unwrap();
 }
 
+#[rustfmt::skip]
+fn get_nfo(nfo_file: &PathBuf, output_path: &PathBuf, release_info: &ReleaseInfo, ainfo: &Vec<Probe>, sinfo: &Vec<Probe>) {
+    let mut nfo = format!("{}\n\nVideo\n", output_path.file_name().unwrap().to_string_lossy());
+    for (name, value) in release_info.lines() {
+        nfo = format!("{nfo}  {name}: {value}\n");
+    }
+    nfo.push_str("\nAudio\n");
+    for (idx, track) in ainfo.iter().enumerate() {
+        let s = &track.stream;
+        let codec = if track.file.extension().is_some_and(|e| e == "opus") { "opus" } else { s.codec_name.as_str() };
+        let bitrate = if track.bit_rate() != 0 { format!(", {} kbps", track.bit_rate() / 1000) } else { String::new() };
+        nfo = format!("{nfo}  #{}: {}, {codec}, {} channels{bitrate}\n", idx + 1, s.tags.title.clone().unwrap_or_default(), s.channels.unwrap_or(0));
+    }
+    nfo.push_str("\nSubtitles\n");
+    for (idx, track) in sinfo.iter().enumerate() {
+        let s = &track.stream;
+        nfo = format!("{nfo}  #{}: {}, {}\n", idx + 1, s.tags.title.clone().unwrap_or_default(), s.codec_name);
+    }
+    let mut file = File::create(nfo_file).unwrap();
+    file.write_all(nfo.as_bytes()).unwrap();
+}
+
+// position of the track --default-audio-lang/--default-sub-lang picked, the other tracks of its kind get their default flag removed
+fn default_track(tracks: &Vec<Probe>, language: &Option<String>, kind: &str) -> Option<usize> {
+    let language = language.as_ref()?;
+    let position = tracks.iter().position(|t| t.language().to_639_3() == language);
+    if position.is_none() {
+        eprintln_log!("WARNING: No {kind} track in {language}, leaving the default {kind} track to mkvmerge");
+    }
+    position
+}
+
 fn mux_file(
     video_path: &PathBuf,
     encode: &PathBuf,
@@ -1239,22 +2552,27 @@ fn mux_file(
     sinfo: &Vec<Probe>,
     args: &Args,
 ) {
-    let atracks: Vec<String> = ainfo.iter().map(|p| format!("{}:{}", p.index.unwrap() + 2, p.stream.index)).collect();
-    let stracks: Vec<String> = sinfo.iter().map(|p| format!("{}:{}", p.index.unwrap() + 2, p.stream.index)).collect();
+    // the encode and the video are files 0 and 1 of the mkvmerge command
+    let file_id = |p: &Probe| p.index.unwrap().checked_add(2).expect("Too many audio and subtitle files!");
+    let atracks: Vec<String> = ainfo.iter().map(|p| format!("{}:{}", file_id(p), p.stream.index)).collect();
+    let stracks: Vec<String> = sinfo.iter().map(|p| format!("{}:{}", file_id(p), p.stream.index)).collect();
     let track_order = [vec!["1:0".to_string()], atracks, stracks].concat().join(",");
     let mut arguments: Vec<String> = vec_into![
-        "--output", output_path.to_str().unwrap(),
+        "--output", path_str(&output_path),
         "-D", "-A", "-S",
-        encode.to_str().unwrap(),
-        "--language", "0:und", "--track-name", format!("0:{}", args.raws), "-t", format!("0:{}", tags.display()),
+        path_str(&encode),
+        "--language", "0:und", "--track-name", format!("0:{}", args.source_label()), "-t", format!("0:{}", tags.display()),
         "--aspect-ratio", format!("0:{}", vinfo[0].ratio()),
-        "--default-duration", format!("0:{}p", vinfo[0].fps()), "-A", "-S",
-        video_path.to_str().unwrap()
+        "--default-duration", format!("0:{}p", args.target_fps.clone().unwrap_or(vinfo[0].fps().to_string())), "-A", "-S",
+        "--compression", format!("0:{}", args.video_compression.as_deref().unwrap_or("none")),
+        path_str(&video_path)
     ];
-    let title = vinfo[0].stream.tags.title.as_ref();
+    let title = args.series_title.as_ref().or(vinfo[0].stream.tags.title.as_ref());
     if title.is_some() {
         arguments = [vec_into!["--title", title.unwrap()], arguments].concat();
     }
+    let default_audio = default_track(ainfo, &args.default_audio_lang, "audio");
+    let default_sub = default_track(sinfo, &args.default_sub_lang, "subtitle");
     let mut audio_files = Vec::new();
     let mut unique_files: HashSet<PathBuf> = HashSet::new();
     for track in ainfo {
@@ -1272,9 +2590,15 @@ fn mux_file(
         }
         let audio_tracks_str = audio_tracks.iter().join(",");
         arguments.append(&mut vec_into!["-a", audio_tracks_str, "-D", "-S"]);
-        for track in ainfo {
+        for (position, track) in ainfo.iter().enumerate() {
             if track.file == path {
                 arguments.append(&mut vec_into!["--track-name", format!("{}:{}", track.stream.index, track.stream.tags.title.as_ref().unwrap()), "--language", format!("{}:{}", track.stream.index, track.language().to_639_3()), "-y", format!("{}:{}", track.stream.index, track.offset)]);
+                if let Some(compression) = &args.audio_compression {
+                    arguments.append(&mut vec_into!["--compression", format!("{}:{compression}", track.stream.index)]);
+                }
+                if let Some(default) = default_audio {
+                    arguments.append(&mut vec_into!["--default-track-flag", format!("{}:{}", track.stream.index, if position == default { "yes" } else { "no" })]);
+                }
             }
         }
         arguments.push(path.to_string_lossy().to_string());
@@ -1295,114 +2619,384 @@ fn mux_file(
             }
         }
         let sub_tracks_str = sub_tracks.iter().join(",");
-        arguments.append(&mut vec_into!["-s", sub_tracks_str, "-D", "-A", "--compression", "-1:zlib"]);
-        for track in sinfo {
+        arguments.append(&mut vec_into!["-s", sub_tracks_str, "-D", "-A", "--compression", format!("-1:{}", args.sub_compression)]);
+        for (position, track) in sinfo.iter().enumerate() {
             if track.file == path {
-                arguments.append(&mut vec_into!["--track-name", format!("{}:{}", track.stream.index, track.stream.tags.title.as_ref().unwrap()), "--language", format!("{}:{}", track.stream.index, track.language().to_639_3()), "-y", format!("{}:{}", track.stream.index, track.offset)])
+                arguments.append(&mut vec_into!["--track-name", format!("{}:{}", track.stream.index, track.stream.tags.title.as_ref().unwrap()), "--language", format!("{}:{}", track.stream.index, track.language().to_639_3()), "-y", format!("{}:{}", track.stream.index, track.offset)]);
+                if let Some(default) = default_sub {
+                    arguments.append(&mut vec_into!["--default-track-flag", format!("{}:{}", track.stream.index, if position == default { "yes" } else { "no" })]);
+                }
             }
         }
         arguments.push(path.to_string_lossy().to_string());
     }
     arguments.append(&mut vec_into!["--track-order", track_order]);
+    // the tags go into every piece with their track
+    if let Some(split) = &args.split {
+        arguments.append(&mut vec_into!["--split", split]);
+    }
     Command::new(get_binary("mkvmerge"))
         .args(&arguments)
-        .spawn().unwrap().wait().unwrap();
-    if output_path.try_exists().is_ok_and(|b| b==false) {
+        .logged().spawn().unwrap().wait().unwrap();
+    if !is_muxed(output_path, args) {
         panic!("mkvmerge failed to create output video!");
     }
 }
 
+// mkvmerge numbers the pieces of a --split output as <name>-001.mkv and so on
+fn split_segments(output_path: &PathBuf) -> Vec<PathBuf> {
+    let prefix = format!("{}-", path_str(&output_path.file_stem().unwrap()));
+    let Ok(entries) = output_path.parent().unwrap().read_dir() else {
+        return Vec::new();
+    };
+    entries.map(|e| e.unwrap().path()).filter(|path| {
+        let name = path_str(&path.file_name().unwrap());
+        let number = name.strip_prefix(prefix.as_str()).and_then(|rest| rest.strip_suffix(".mkv"));
+        number.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    }).sorted().collect()
+}
+
+fn is_muxed(output_path: &PathBuf, args: &Args) -> bool {
+    if args.split.is_some() {
+        !split_segments(output_path).is_empty()
+    } else {
+        output_path.try_exists().is_ok_and(|b| b == true)
+    }
+}
+
+// size plus the first and last 16 MiB, hashing whole remuxes would take longer than some encodes
+fn source_fingerprint(file: &PathBuf) -> String {
+    let chunk = 16 * 1024 * 1024;
+    let mut reader = File::open(file).unwrap();
+    let size = reader.metadata().unwrap().len();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    let mut buffer = Vec::new();
+    (&mut reader).take(chunk).read_to_end(&mut buffer).unwrap();
+    if size > chunk * 2 {
+        io::Seek::seek(&mut reader, io::SeekFrom::End(-(chunk as i64))).unwrap();
+    }
+    reader.read_to_end(&mut buffer).unwrap();
+    hasher.update(&buffer);
+    format!("{:x}", hasher.finalize())
+}
+
+// CRC32 and SHA-256 of an output in one streaming pass
+fn output_checksums(file: &PathBuf) -> (String, String) {
+    let mut reader = File::open(file).unwrap();
+    let (mut crc, mut sha) = (crc32fast::Hasher::new(), Sha256::new());
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).unwrap();
+        if read == 0 {
+            break;
+        }
+        crc.update(&buffer[..read]);
+        sha.update(&buffer[..read]);
+    }
+    (format!("{:08X}", crc.finalize()), format!("{:x}", sha.finalize()))
+}
+
+// an output already renamed by --crc-in-name counts as done
+fn crc_named_output(output_path: &PathBuf) -> Option<PathBuf> {
+    let prefix = format!("{} [", path_str(&output_path.file_stem().unwrap()));
+    let outputs = output_path.parent().unwrap().read_dir().ok()?;
+    outputs.map(|e| e.unwrap().path()).find(|path| {
+        let name = path_str(&path.file_name().unwrap());
+        let crc = name.strip_prefix(prefix.as_str()).and_then(|rest| rest.strip_suffix("].mkv"));
+        crc.is_some_and(|crc| crc.len() == 8 && crc.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+fn load_state(state_path: &PathBuf) -> BatchState {
+    if state_path.try_exists().is_ok_and(|b| b == false) {
+        return BatchState::default();
+    }
+    let reader = File::open(state_path).unwrap();
+    serde_json::from_reader(reader).unwrap_or_else(|e| {
+        eprintln_log!("WARNING: {} is unreadable, starting over: {e}", state_path.display());
+        BatchState::default()
+    })
+}
+
+// everything grav1an derived from a source that has since been replaced: scripts, probes, encodes, caches and av1an temps
+fn remove_intermediates(file_path: &PathBuf) {
+    let (stem, name) = (path_str(&file_path.file_stem().unwrap()), path_str(&file_path.file_name().unwrap()));
+    remove_intermediates_in(file_path, file_path.parent().unwrap(), &stem, &name);
+    if let Some((dir, _)) = TEMP_DIR.get() {
+        let relocated = |path: PathBuf| path_str(&relocate(path, dir).file_name().unwrap());
+        remove_intermediates_in(file_path, dir, &relocated(temp_path(file_path, "")), &relocated(file_path.clone()));
+    }
+}
+
+// only the exact names grav1an gives its intermediates, "Show - 01.5.mkv" or a "Show - 01_extras" folder aren't touched when "Show - 01" is cleaned
+fn is_intermediate(file: &str, stem: &str, source_name: &str) -> bool {
+    let named = ["_scenes.json", "_skip.json", "_override.json", "_tags.xml", ".vpy", "_sample.json", "_estimate.json"];
+    let probe = Regex::new(r"^_q[+-]\d+(\.mkv|\.ssimu2)$").unwrap();
+    let audio = Regex::new(r"^\.\d+\.[a-z]{3}\.opus$").unwrap();
+    let cache = Regex::new(r"^\.(ffprobe|lwi|\d+\.bps)$").unwrap();
+    if let Some(rest) = file.strip_prefix(source_name) {
+        if cache.is_match(rest).unwrap_or(false) {
+            return true;
+        }
+    }
+    let Some(rest) = file.strip_prefix(stem) else {
+        return false;
+    };
+    // probe scores are cached as <stem>_low.ssimu2 next to <stem>_low.mkv
+    let scored = rest.strip_suffix(".ssimu2").map(|probe| format!("{probe}.mkv"));
+    TEMP_EXTENSIONS.iter().chain(named.iter()).any(|suffix| rest == *suffix || scored.as_deref() == Some(*suffix))
+        || probe.is_match(rest).unwrap_or(false)
+        || audio.is_match(rest).unwrap_or(false)
+}
+
+fn remove_intermediates_in(file_path: &PathBuf, dir: &Path, stem: &str, source_name: &str) {
+    // the av1an temps of the encode, --sample-scenes and --estimate
+    let temps = [stem.to_string(), format!("{stem}_sample"), format!("{stem}_estimate")];
+    for entry in dir.read_dir().unwrap() {
+        let path = entry.unwrap().path();
+        let name = path_str(&path.file_name().unwrap());
+        if path == *file_path {
+            continue;
+        }
+        let result = if path.is_dir() && temps.contains(&name) {
+            fs::remove_dir_all(&path)
+        } else if path.is_file() && is_intermediate(&name, stem, source_name) {
+            fs::remove_file(&path)
+        } else {
+            continue;
+        };
+        if let Err(e) = result {
+            eprintln_log!("WARNING: Failed to remove {}: {e}", path.display());
+        }
+    }
+}
+
+// the 2nd sources of one episode, only the files in --src2-directory that belong to it and not the whole folder
+fn matched_src2(file_path: &PathBuf, episode_number: &String, args: &Args) -> Option<Vec<PathBuf>> {
+    if args.src2_file.is_some() {
+        return Some(vec![args.src2_file.clone().unwrap()]);
+    }
+    if !((args.audio == "2" || args.audio == "both") || (args.subs == "2" || args.subs == "both") || args.lehmer_merge) {
+        return None;
+    }
+    let base = file_path.file_stem().unwrap();
+    let src2_directory = args.src2_directory.clone().unwrap();
+    let matches: Vec<PathBuf> = src2_directory.read_dir().unwrap().map(|file| file.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_os_string();
+            let matches = if !args.not_show {
+                match_episode(&name, episode_number.clone(), args.season.clone())
+            } else {
+                path.file_stem().unwrap() == base
+            };
+            is_video(path) && !is_temporary_file(&name) && matches
+        }).collect();
+    if matches.is_empty() {
+        eprintln_log!("WARNING: No file in {} matches {}", src2_directory.display(), file_path.display());
+        return None;
+    }
+    Some(pick_src2(matches, file_path, args))
+}
+
+// a season folder with several releases of an episode would mix their tracks, --src2-prefer picks one of them
+fn pick_src2(matches: Vec<PathBuf>, file_path: &PathBuf, args: &Args) -> Vec<PathBuf> {
+    if matches.len() < 2 {
+        return matches;
+    }
+    let preferred: Vec<PathBuf> = match &args.src2_prefer {
+        Some(prefer) => matches.iter().filter(|m| path_str(&m.file_name().unwrap()).contains(prefer.as_str())).cloned().collect(),
+        None => Vec::new(),
+    };
+    if preferred.len() == 1 {
+        return preferred;
+    }
+    let names = matches.iter().map(|m| path_str(&m.file_name().unwrap())).join("\n  ");
+    panic!("{} 2nd sources match {}, pick one with --src2-prefer:\n  {names}", matches.len(), file_path.display());
+}
+
+// --resume-batch: broken scenes files and outputs are removed, the mkv intermediates are checked again when they're reached.
+// Returns whether the output is still there.
+fn resume_plan(file_path: &PathBuf, output_path: &PathBuf, args: &Args) -> bool {
+    let [scenes, scenes_skip, scenes_over] = ["_scenes.json", "_skip.json", "_override.json"].map(|suffix| temp_path(file_path, suffix));
+    for json in [&scenes, &scenes_skip, &scenes_over] {
+        let readable = File::open(json).ok().is_some_and(|f| serde_json::from_reader::<_, ScenesInfo>(f).is_ok());
+        if json.try_exists().is_ok_and(|b| b == true) && !readable {
+            eprintln_log!("WARNING: {} is unreadable, creating it again", json.display());
+            let _ = fs::remove_file(json);
+        }
+    }
+    let frames = scene_frames(&scenes);
+    let grain_pass = !args.no_grain && args.native_photon_noise().is_none();
+    let output_done = !args.to_stdout() && is_valid_intermediate(output_path, frames);
+    let plan = if output_done {
+        "done"
+    } else if grain_pass && is_valid_intermediate(&temp_path(file_path, "_grained.mkv"), frames) {
+        "muxing the grained encode"
+    } else if is_valid_intermediate(&temp_path(file_path, "_enc.mkv"), frames) {
+        if grain_pass { "adding grain to the encode" } else { "muxing the encode" }
+    } else if scenes_over.try_exists().is_ok_and(|b| b == true) || (args.single_pass && frames.is_some()) {
+        "final encode"
+    } else if frames.is_some() {
+        "target quality probes"
+    } else {
+        "the start"
+    };
+    println_log!("Resuming {} from {plan}", file_path.display());
+    output_done
+}
+
+// failures end the run with a panic, so whatever got here was either processed or skipped
+fn print_summary(processed: usize, skipped: &BTreeMap<&'static str, usize>) {
+    let reasons = skipped.iter().map(|(reason, count)| format!("{count} {reason}")).join(", ");
+    let total: usize = skipped.values().sum();
+    if total == 0 {
+        println_log!("Processed {processed}, skipped 0");
+    } else {
+        println_log!("Processed {processed}, skipped {total} ({reasons})");
+    }
+    if processed == 0 && skipped.iter().all(|(reason, _)| *reason == "not a video file" || *reason == "no episode number") {
+        eprintln_log!("WARNING: Nothing was encoded! Check that the input directory holds mkv/mp4/webm/avi/mov/ts/m2t/y4m files, fix episode numbers with --episode-pattern, or use --not-show for movies");
+    }
+}
+
 fn process_command(args: Args) {
-    println!("Input directory: {:#?}", args.input_directory);
+    println_log!("Input directory: {:#?}", args.input_directory);
     let input_directory_exists = args.input_directory.try_exists().unwrap();
     assert!(input_directory_exists, "Input directory does not exist!");
     let mut torrent_path: Option<PathBuf> = None;
     let mut torrent_files: Option<PathBuf> = None;
     let mut src2_paths: Option<Vec<PathBuf>> = None;
-    let mut encoder_options: Option<String> = None;
-    for path in args.input_directory.read_dir().unwrap() {
-        let dir_entry = path.unwrap();
-        let file_path = dir_entry.path();
-        let file_name = dir_entry.file_name();
+    let mut release_info: Option<ReleaseInfo> = None;
+    let sources: Vec<PathBuf> = if let Some(file) = &args.input_file {
+        vec![file.clone()]
+    } else {
+        args.input_directory.read_dir().unwrap().map(|e| e.unwrap().path()).collect()
+    };
+    let mut skipped: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let not_video = sources.iter().filter(|s| s.is_file() && !is_video(s) && !is_temporary_file(&s.file_name().unwrap().to_os_string())).count();
+    if not_video > 0 {
+        skipped.insert("not a video file", not_video);
+    }
+    let mut processed = 0usize;
+    let mut estimated = (0f64, 0u64);
+    let sources = join_parts(sources);
+    let episodes = sources.len();
+    // skipped episodes are taken out of the length so the eta only reflects actual encodes
+    let progress = batch_progress(episodes as u64);
+    let state_path = args.output_directory.join(".grav1an-state.json");
+    let mut state = load_state(&state_path);
+    for file_path in sources {
+        interrupt::check();
         let base = file_path.file_stem().unwrap();
-        if !is_video(&file_path) || is_temporary_file(&file_name) {
-            continue;
-        }
-        println!("{}", dir_entry.path().display());
+        println_log!("{}", file_path.display());
+        let mut args = args.with_sidecar(&file_path);
         let episode_number_try = if !args.not_show {
             extract_episode_number(&base, args.episode_pattern.clone(), Some(args.season.clone()))
         } else {
             Err("Argument 'not_show' is set!".into())
         };
         if episode_number_try.is_err() && !args.not_show {
-            println!("Failed to get episode number from {base:#?}");
+            println_log!("Failed to get episode number from {base:#?}");
+            *skipped.entry("no episode number").or_default() += 1;
+            progress.dec_length(1);
             continue;
         }
         let episode_number = episode_number_try.unwrap_or("".into());
         if !args.not_show {
-            println!("Episode {episode_number}");
+            println_log!("Episode {episode_number}");
         }
         let filename_output = if args.inherit_name { 
-            base.to_string_lossy().to_string()
+            base.to_string_lossy().trim_end_matches("_joined").to_string()
         } else if !args.not_show {
-            format!("[{}] {} - {episode_number} [{}]", args.group, args.name, args.suffix)
+            format!("[{}] {} - {episode_number} [{}]", args.group, args.series_name(), args.name_suffix())
         } else {
-            format!("[{}] {} [{}]", args.group, args.name, args.suffix)
+            format!("[{}] {} [{}]", args.group, args.series_name(), args.name_suffix())
         };
-        let output_path = args.output_directory.clone().join(format!("{filename_output}.mkv"));
-        println!("Output path: {}", output_path.display());
+        let output_directory = args.episode_output_directory();
+        if args.output_template.is_some() {
+            fs::create_dir_all(&output_directory).expect(format!("Failed to create output directory {}", output_directory.display()).as_str());
+        }
+        let mut output_path = if args.to_stdout() {
+            temp_path(&file_path, "_stdout.mkv")
+        } else if args.split.is_some() {
+            let pieces = output_directory.join(&filename_output);
+            fs::create_dir_all(&pieces).expect(format!("Failed to create output directory {}", pieces.display()).as_str());
+            pieces.join(format!("{filename_output}.mkv"))
+        } else {
+            output_directory.join(format!("{filename_output}.mkv"))
+        };
+        if args.crc_in_name {
+            output_path = crc_named_output(&output_path).unwrap_or(output_path);
+        }
+        println_log!("Output path: {}", output_path.display());
         if args.batch {
             torrent_files = Some(args.output_directory.clone());
             torrent_path = Some(args.input_directory.clone().join(format!(
                     "{}.torrent",
-                    args.output_directory.clone().file_stem().unwrap().to_str().unwrap())));
+                    path_str(&args.output_directory.clone().file_stem().unwrap()))));
         } else {
-            torrent_files = Some(output_path.clone());
+            torrent_files = Some(if args.split.is_some() { output_path.parent().unwrap().to_path_buf() } else { output_path.clone() });
             torrent_path = Some(args.input_directory.clone().join(format!("{filename_output}.torrent")));
         }
+        let had_output = output_path.try_exists().is_ok_and(|b| b == true);
+        if args.resume_batch && !resume_plan(&file_path, &output_path, &args) && had_output && !args.batch {
+            // the torrent of an output that had to be removed is made again with it
+            let _ = fs::remove_file(torrent_path.as_ref().unwrap());
+        }
+        let state_key = path_str(&abs(&file_path).unwrap());
+        let fingerprint = args.incremental.then(|| source_fingerprint(&file_path));
+        if let (Some(fingerprint), Some(previous)) = (&fingerprint, state.episodes.get(&state_key)) {
+            if *fingerprint == previous.fingerprint {
+                println_log!("Source unchanged since {} was made, skipping!", previous.output.display());
+                *skipped.entry("unchanged since the last run").or_default() += 1;
+                progress.dec_length(1);
+                continue;
+            }
+            println_log!("Source changed since {} was made, encoding it again", previous.output.display());
+            remove_intermediates(&file_path);
+            let _ = fs::remove_file(&previous.output);
+            if !args.batch {
+                let _ = fs::remove_file(torrent_path.as_ref().unwrap());
+            }
+        }
         if !args.no_torrent
             && torrent_path.clone().unwrap().try_exists().is_ok_and(|b| b == true)
-            || args.no_torrent && output_path.clone().try_exists().is_ok_and(|b| b == true)
+            || args.no_torrent && !args.to_stdout() && is_muxed(&output_path, &args)
         {
             if !args.no_torrent {
-                println!("Torrent file exists, skipping!");
+                println_log!("Torrent file exists, skipping!");
+                *skipped.entry("torrent exists").or_default() += 1;
             } else {
-                println!("Output file exists, skipping!");
+                println_log!("Output file exists, skipping!");
+                *skipped.entry("output exists").or_default() += 1;
             }
+            progress.dec_length(1);
             continue;
         }
-        if (args.audio == "2" || args.audio == "both") || (args.subs == "2" || args.subs == "both")
-        {
-            let mut temp_files = args.src2_directory.clone().unwrap().read_dir().unwrap()
-                .filter(|file| {
-                    let hi = file.as_ref().unwrap().file_name();
-                    let matches = if !args.not_show {
-                        match_episode(&hi, episode_number.clone(), args.season.clone())
-                    } else {
-                        file.as_ref().unwrap().path().file_stem().unwrap() == base
-                    };
-                    is_video(&file.as_ref().unwrap().path())
-                        && !is_temporary_file(&hi)
-                        && matches
-                }).peekable();
-            if temp_files.peek().is_some() {
-                let mut temp_list: Vec<PathBuf> = vec![];
-                temp_files.for_each(|file| {
-                    temp_list.push(args.src2_directory.clone().unwrap().join(file.unwrap().path()),)
-                });
-                src2_paths = Some(temp_list.clone());
-            }
+        check_disk_space(&file_path, &args);
+        src2_paths = matched_src2(&file_path, &episode_number, &args);
+        let (mut vinfo, ainfo, sinfo) = get_info(&file_path, &src2_paths, &args);
+        check_chroma(&vinfo[0], &mut args);
+        check_hdr(&mut vinfo[0], &mut args);
+        if let Some(fps) = &args.fps_override {
+            // everything goes through Probe::fps, so replacing the probed rate is enough
+            vinfo[0].stream.avg_frame_rate = Some(fps.clone());
+        }
+        check_target_fps(&vinfo[0], &args);
+        if let Some(probe_frames) = args.probe_frames {
+            args.cycle = probe_cycle(&vinfo[0], probe_frames, args.cycle);
         }
-        let (vinfo, ainfo, sinfo) = get_info(&file_path, &args.src2_directory, &args);
         let (cr, matrix, transfer, primaries) = vinfo[0].color_data(args.encoder == "rav1e");
-        encoder_options = Some(get_encoder_params(&args, &vinfo, None, None, None, true));
-        let multi_speed: u8 = if args.encoder == "rav1e" { 10 } else { 8 };
+        release_info = Some(ReleaseInfo::new(get_encoder_params(&args, &vinfo, None, None, None, true), &args));
+        let multi_speed = args.probe_speed;
 
         let scene_detect = temp_path(&file_path, "_scene_detect.vpy");
         let skip_frames = temp_path(&file_path, "_skip.vpy");
         let script = temp_path(&file_path, ".vpy");
         let clean = temp_path(&file_path, "_clean.vpy");
         let merge = temp_path(&file_path, "_merge.vpy");
+        let grain = temp_path(&file_path, "_grain.vpy");
         let scenes = temp_path(&file_path, "_scenes.json");
         let scenes_skip = temp_path(&file_path, "_skip.json");
         let scenes_over = temp_path(&file_path, "_override.json");
@@ -1415,6 +3009,8 @@ fn process_command(args: Args) {
         if scene_detect.try_exists().is_ok_and(|b| b == false) {
             sd_script(&scene_detect, &args, &vinfo);
         }
+        // a script source is the filter chain itself, the scene detection script adds the --target-fps conversion on top of it
+        let script = if is_script(&file_path) { if args.target_fps.is_some() { scene_detect.clone() } else { file_path.clone() } } else { script };
         if script.try_exists().is_ok_and(|b| b == false) && !args.no_filter {
             create_vpy_script(&script, &file_path, &args, &vinfo);
         }
@@ -1427,64 +3023,86 @@ fn process_command(args: Args) {
         if merge.try_exists().is_ok_and(|b| b == false) && args.lehmer_merge {
             merge_script(&merge, &args, &vinfo);
         }
+        if grain.try_exists().is_ok_and(|b| b == false) && args.adaptive_grain.is_some() {
+            grain_script(&grain, &args, &vinfo);
+        }
         if args.review {
-            println!("PAUSED: Review and edit your filters for {}. Ready to continue?", file_path.display());
-            print!("(yes/no): ");
-            io::stdout().flush().expect("Failed to flush!");
-            let mut input: String = String::new();
-            io::stdin().read_line(&mut input).expect("Failed to read input!");
-            if input != "yes\n" {
-                eprintln!("\nAborted. Exiting script.");
+            let question = format!("Review and edit your filters for {}. Ready to continue?", file_path.display());
+            if !confirm(question.as_str(), args.review_timeout) {
+                eprintln_log!("\nAborted. Exiting script.");
                 exit(0);
             }
-            println!("Continuing to encode.");
+            println_log!("Continuing to encode.");
         }
-        if encode.try_exists().is_ok_and(|b| b == false) {
+        interrupt::check();
+        if !is_valid_intermediate(&encode, scene_frames(&scenes)) || args.sample_scenes.is_some() || args.estimate.is_some() {
             let scenes_file;
-            let temp = file_path.parent().unwrap().join(base);
+            let temp = av1an_temp(file_path.parent().unwrap().join(base));
+            if args.scenes.is_some() && scenes.try_exists().is_ok_and(|b| b == false) {
+                let external = args.scenes.as_ref().unwrap();
+                let scenes_read = File::open(external).expect("Failed to open scenes file!");
+                let scenes_info: ScenesInfo = serde_json::from_reader(scenes_read).unwrap_or_else(|e| panic!("{} isn't a valid av1an scenes file: {e}", external.display()));
+                let writer = File::create(&scenes).unwrap();
+                serde_json::to_writer(writer, &scenes_info).unwrap();
+            }
             if scenes.try_exists().is_ok_and(|b| b == false) {
                 scene_detection(&scene_detect, &encode, &scenes, &temp, &args, &vinfo);
             }
+            if let Some(count) = args.estimate {
+                let input = if args.no_filter { &scene_detect } else { &script };
+                let (time, size) = estimate_encode(input, &scenes, &file_path, count, &args, &vinfo);
+                estimated = (estimated.0 + time, estimated.1 + size);
+                processed += 1;
+                progress.inc(1);
+                continue;
+            }
             if !args.single_pass {
                 if scenes_over.try_exists().is_ok_and(|b| b == false) {
                     let scenes_info_read = File::open(&scenes).unwrap();
                     let mut scenes_info: ScenesInfo = serde_json::from_reader(&scenes_info_read).unwrap();
                     if scenes_skip.try_exists().is_ok_and(|b| b == false) {
-                        scene_detection(&skip_frames, &encode, &scenes_skip, &temp, &args, &vinfo);
-                    }
-                    let lowest_quantizer = calculate_quantizer(&args, 2);
-                    let lowest = temp_path(&file_path, "_lowest.mkv");
-                    let lowest_temp = file_path.parent().unwrap().join(lowest.file_stem().unwrap());
-                    if lowest.try_exists().is_ok_and(|b| b == false) {
-                        encode_file(&skip_frames, &skip_frames, &lowest, &lowest_temp, &scenes_skip, Some(multi_speed), Some(lowest_quantizer), None, false, &args, &vinfo);
+                        skip_scenes(&scenes, &scenes_skip, &args);
                     }
-                    get_ssimulacra2(&skip_frames, &lowest, &mut scenes_info, lowest_quantizer, &args, &cr, &matrix, &transfer, &primaries);
-
-                    let low_quantizer = calculate_quantizer(&args, 1);
-                    let low = temp_path(&file_path, "_low.mkv");
-                    let low_temp = file_path.parent().unwrap().join(low.file_stem().unwrap());
-                    if low.try_exists().is_ok_and(|b| b == false) {
-                        encode_file(&skip_frames, &skip_frames, &low, &low_temp, &scenes_skip, Some(multi_speed), Some(low_quantizer), None, false, &args, &vinfo);
+                    let shared_path = args.output_directory.join(".grav1an-probes.json");
+                    let mut shared: SharedProbes = File::open(&shared_path).ok().and_then(|f| serde_json::from_reader(f).ok()).unwrap_or_default();
+                    #[rustfmt::skip]
+                    let mut fit = SharedFit { source: path_str(&file_path.file_name().unwrap()), group: source_group(&file_path), width: vinfo[0].stream.width, height: vinfo[0].stream.height, duration: vinfo[0].duration(), coefficients: Vec::new() };
+                    let reused = shared.episodes.iter().find(|f| args.share_probes && f.similar(&fit)).cloned();
+                    let probes: Vec<(f32, PathBuf)> = if reused.is_some() {
+                        vec![(calculate_quantizer(&args, 0), temp_path(&file_path, "_verify.mkv"))]
+                    } else {
+                        args.probe_points.iter().unique()
+                            .map(|modifier| (calculate_quantizer(&args, *modifier), temp_path(&file_path, probe_suffix(*modifier).as_str()))).collect()
+                    };
+                    for batch in probes.chunks(args.probe_jobs as usize) {
+                        let results: Vec<BTreeMap<usize, f64>> = std::thread::scope(|scope| {
+                            let (args, vinfo, skip_frames, scenes_skip, color) = (&args, &vinfo, &skip_frames, &scenes_skip, (&cr, &matrix, &transfer, &primaries));
+                            let handles: Vec<_> = batch.iter().map(|(quantizer, probe)| {
+                                scope.spawn(move || probe_encode(skip_frames, scenes_skip, probe, *quantizer, multi_speed, args, vinfo, color))
+                            }).collect();
+                            handles.into_iter().map(|handle| handle.join().expect("Probe encode failed!")).collect()
+                        });
+                        for ((quantizer, _), scores) in batch.iter().zip(results) {
+                            add_quantizer_scores(&mut scenes_info, scores, *quantizer);
+                        }
                     }
-                    get_ssimulacra2(&skip_frames, &low, &mut scenes_info, low_quantizer, &args, &cr, &matrix, &transfer, &primaries);
 
-                    let high_quantizer = calculate_quantizer(&args, -1);
-                    let high = temp_path(&file_path, "_high.mkv");
-                    let high_temp = file_path.parent().unwrap().join(high.file_stem().unwrap());
-                    if high.try_exists().is_ok_and(|b| b == false) {
-                        encode_file(&skip_frames, &skip_frames, &high, &high_temp, &scenes_skip, Some(multi_speed), Some(high_quantizer), None, false, &args, &vinfo);
+                    if let Some(reused) = &reused {
+                        println_log!("Reusing the probes of {}, verified with one probe", reused.source);
+                    } else if args.share_probes {
+                        fit.coefficients = episode_fit(&scenes_info, &args);
+                        shared.episodes.push(fit);
+                        serde_json::to_writer_pretty(File::create(&shared_path).unwrap(), &shared).unwrap();
                     }
-                    get_ssimulacra2(&skip_frames, &high, &mut scenes_info, high_quantizer, &args, &cr, &matrix, &transfer, &primaries);
-
-                    let highest_quantizer = calculate_quantizer(&args, -2);
-                    let highest = temp_path(&file_path, "_highest.mkv");
-                    let highest_temp = file_path.parent().unwrap().join(highest.file_stem().unwrap());
-                    if highest.try_exists().is_ok_and(|b| b == false) {
-                        encode_file(&skip_frames, &skip_frames, &highest, &highest_temp, &scenes_skip, Some(multi_speed), Some(highest_quantizer), None, false, &args, &vinfo);
+                    // measured first, a run stopped in between would otherwise resume with uniform grain
+                    let levels = args.adaptive_grain.is_some().then(|| {
+                        println_log!("Measuring grain");
+                        get_grain_levels(&grain, args.cycle)
+                    });
+                    zone_overrides(&mut scenes_info, &scenes, &scenes_over, &args, &cr, &matrix, &transfer, &primaries, reused.as_ref().map(|f| &f.coefficients));
+                    if let Some(levels) = levels {
+                        adaptive_grain(&scenes_over, &levels, &args);
                     }
-                    get_ssimulacra2(&skip_frames, &highest, &mut scenes_info, highest_quantizer, &args, &cr, &matrix, &transfer, &primaries);
-
-                    zone_overrides(&mut scenes_info, &scenes, &scenes_over, &args, &cr, &matrix, &transfer, &primaries);
                 }
                 scenes_file = scenes_over.clone();
             } else {
@@ -1493,82 +3111,321 @@ fn process_command(args: Args) {
             if args.parameters.is_some() && !args.single_pass {
                 validate_overrides(&scenes_file, &args);
             }
-            encode_file(&scene_detect, &script, &encode, &temp, &scenes_file, Some(args.speed), Some(args.quantizer), None, true, &args, &vinfo);
+            if let Some(count) = args.sample_scenes {
+                let input = if args.no_filter { &scene_detect } else { &script };
+                let sample = output_directory.join(format!("{filename_output}_sample.mkv"));
+                encode_sample(input, &scenes_file, &file_path, &sample, count, &args, &vinfo);
+                processed += 1;
+                progress.inc(1);
+                continue;
+            }
+            encode_file(&scene_detect, &script, &encode, &temp, &scenes_file, Some(args.speed), Some(args.quantizer), None, true, args.native_photon_noise(), &args, &vinfo);
         }
-        if grained.try_exists().is_ok_and(|b| b == false) {
+        interrupt::check();
+        if !is_valid_intermediate(&grained, scene_frames(&scenes)) {
             if args.diff_grain {
-                if grainy.try_exists().is_ok_and(|b| b == false) {
+                if !is_valid_intermediate(&grainy, scene_frames(&scenes)) {
                     let script = if args.lehmer_merge {
                         merge
                     } else {
                         scene_detect.clone()
                     };
-                    let temp = file_path.parent().unwrap().join(grainy.file_stem().unwrap());
-                    encode_file(&scene_detect, &script, &grainy, &temp, &scenes, None, None, Some("x264"), true, &args, &vinfo);
+                    let temp = av1an_temp(temp_path(&grainy, ""));
+                    encode_file(&scene_detect, &script, &grainy, &temp, &scenes, None, None, Some(args.grain_ref_encoder.as_str()), true, None, &args, &vinfo);
                 }
                 let cleaned_temp = if args.no_filter {
-                    let cleaned_temp = file_path.parent().unwrap().join(cleaned.file_stem().unwrap());
-                    if cleaned.try_exists().is_ok_and(|b| b == false) {
+                    let cleaned_temp = av1an_temp(temp_path(&cleaned, ""));
+                    if !is_valid_intermediate(&cleaned, scene_frames(&scenes)) {
                         let scenes_file = if args.single_pass {
                             scenes.clone()
                         } else {
                             scenes_over.clone()
                         };
-                        encode_file(&clean, &clean, &cleaned, &cleaned_temp, &scenes_file, Some(multi_speed), None, None, true, &args, &vinfo);
+                        encode_file(&clean, &clean, &cleaned, &cleaned_temp, &scenes_file, Some(multi_speed), None, None, true, None, &args, &vinfo);
                     }
                     cleaned_temp
                 } else {
-                    file_path.parent().unwrap().join(file_path.file_stem().unwrap())
+                    av1an_temp(temp_path(&file_path, ""))
                 };
-                let grainy_temp = temp_path(&grainy, "");
-                get_diff_grain(&grainy_temp, &cleaned_temp, &grainy_temp, &grained);
-            } else if !args.no_grain {
-                add_grain_table(&encode, &grained, args.photon_noise);
+                let grainy_temp = av1an_temp(temp_path(&grainy, ""));
+                get_diff_grain(&grainy_temp, &cleaned_temp, &grainy_temp, &grained, chunk_extension(&args.grain_ref_encoder));
+            } else if !args.no_grain && args.native_photon_noise().is_none() {
+                add_grain_table(&encode, &grained, args.photon_noise, &args);
             }
+            interrupt::discard(&[&grained]);
+        }
+        cleanup_temp(&av1an_temp(temp_path(&file_path, "")), &args);
+        if args.diff_grain {
+            cleanup_temp(&av1an_temp(temp_path(&grainy, "")), &args);
+            cleanup_temp(&av1an_temp(temp_path(&cleaned, "")), &args);
         }
         if tags.try_exists().is_ok_and(|b| b == false) {
-            get_tags(&tags, Some(get_encoder_params(&args, &vinfo, None, None, None, true)), &args);
+            get_tags(&tags, release_info.as_ref().unwrap());
         }
         if args.review {
-            println!("PAUSED: Review and edit your tags for {}. Ready to continue?", file_path.display());
-            print!("(yes/no): ");
-            io::stdout().flush().expect("Failed to flush!");
-            let mut input: String = String::new();
-            io::stdin().read_line(&mut input).expect("Failed to read input!");
-            if input.to_lowercase() != "yes\n" {
-                eprintln!("\nAborted. Exiting script.");
+            let question = format!("Review and edit your tags for {}. Ready to continue?", file_path.display());
+            if !confirm(question.as_str(), args.review_timeout) {
+                eprintln_log!("\nAborted. Exiting script.");
                 exit(0);
             }
-            println!("Continuing to mux.");
+            println_log!("Continuing to mux.");
         }
-        if output_path.try_exists().is_ok_and(|b| b == false) {
-            let video_path = if args.no_grain {
+        interrupt::check();
+        let mut checksums: Option<(String, String)> = None;
+        if !is_muxed(&output_path, &args) || args.to_stdout() {
+            // native grain is already in the encode
+            let video_path = if args.no_grain || args.native_photon_noise().is_some() {
                 encode.clone()
             } else {
                 grained.clone()
             };
             mux_file(&video_path, &encode, &output_path, &tags, &vinfo, &ainfo, &sinfo, &args);
-            println!("{filename_output} done!");
+            let segments = split_segments(&output_path);
+            interrupt::discard(&[[&output_path].as_slice(), &segments.iter().collect::<Vec<_>>()].concat());
+            if args.crc_in_name {
+                let (crc, sha) = output_checksums(&output_path);
+                let renamed = output_path.with_file_name(format!("{filename_output} [{crc}].mkv"));
+                fs::rename(&output_path, &renamed).unwrap();
+                output_path = renamed;
+                checksums = Some((crc, sha));
+                if !args.batch {
+                    torrent_files = Some(output_path.clone());
+                }
+            }
+            println_log!("{filename_output} done!");
+        }
+        let sha256 = PathBuf::from(format!("{}.sha256", output_path.display()));
+        if args.sha256 && sha256.try_exists().is_ok_and(|b| b == false) {
+            let (_, sha) = checksums.unwrap_or_else(|| output_checksums(&output_path));
+            fs::write(&sha256, format!("{sha}  {}\n", path_str(&output_path.file_name().unwrap()))).unwrap();
+        }
+        if args.to_stdout() {
+            let mut muxed = File::open(&output_path).unwrap();
+            io::copy(&mut muxed, &mut STDOUT_PIPE.get().unwrap()).expect("Failed to write the output to stdout!");
+            fs::remove_file(&output_path).unwrap();
+        }
+        let nfo = output_path.with_extension("nfo");
+        if args.nfo && nfo.try_exists().is_ok_and(|b| b == false) {
+            get_nfo(&nfo, &output_path, release_info.as_ref().unwrap(), &ainfo, &sinfo);
         }
         if !args.batch && !args.no_torrent && torrent_path.clone().unwrap().try_exists().is_ok_and(|b| b == false) {
-            let opus_options: String = if src2_paths.is_some() {
+            let opus_options: String = if args.audio == "0" || args.audio == "none" {
+                String::new()
+            } else if src2_paths.is_some() {
                 check_audio_encoding(&args.src2_directory.clone().unwrap())
             } else {
                 check_audio_encoding(&args.input_directory.clone())
             };
-            create_torrent(opus_options, encoder_options.clone().unwrap(), &torrent_path.clone().unwrap(), &torrent_files.clone().unwrap(), &args);
+            create_torrent(opus_options, release_info.as_ref().unwrap(), &torrent_path.clone().unwrap(), &torrent_files.clone().unwrap(), &args);
         }
+        if let Some(fingerprint) = fingerprint {
+            state.episodes.insert(state_key, EpisodeState { fingerprint, output: output_path.clone() });
+            let writer = File::create(&state_path).unwrap();
+            serde_json::to_writer_pretty(writer, &state).unwrap();
+        }
+        processed += 1;
+        progress.inc(1);
+    }
+    progress.finish();
+    print_summary(processed, &skipped);
+    if args.estimate.is_some() {
+        println_log!("Estimated total: {} and {:.2} GB, about ±30%", format_seconds(estimated.0), estimated.1 as f64 / 1e9);
+        return;
     }
     if args.batch &&
         !args.no_torrent &&
+        release_info.is_some() &&
+        args.sample_scenes.is_none() &&
         torrent_path.clone().is_some() &&
         torrent_path.clone().unwrap().try_exists().is_ok_and(|b| b == false)
     {
-        let opus_options: String = if src2_paths.is_some() {
+        let opus_options: String = if args.audio == "0" || args.audio == "none" {
+            String::new()
+        } else if src2_paths.is_some() {
             check_audio_encoding(&args.src2_directory.clone().unwrap())
         } else {
             check_audio_encoding(&args.input_directory.clone())
         };
-        create_torrent(opus_options, encoder_options.unwrap(), &torrent_path.unwrap(), &torrent_files.unwrap(), &args);
+        create_torrent(opus_options, release_info.as_ref().unwrap(), &torrent_path.unwrap(), &torrent_files.unwrap(), &args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_args(extra: &[&str]) -> Args {
+        Args::parse_from(["grav1an", "-i", "/tmp", "-o", "/tmp/out", "-n", "Show"].iter().chain(extra))
+    }
+
+    fn test_video(file: &str) -> Probe {
+        #[rustfmt::skip]
+        let stream = serde_json::json!({
+            "index": 0, "codec_name": "h264", "codec_type": "video", "avg_frame_rate": "24000/1001", "start_pts": 0,
+            "width": 1920, "height": 1080, "pix_fmt": "yuv420p", "disposition": { "forced": 0 }, "tags": {},
+        });
+        Probe { stream: serde_json::from_value(stream).unwrap(), file: PathBuf::from(file), offset: 0, index: None, src2: false }
+    }
+
+    fn test_audio(codec: &str) -> Probe {
+        #[rustfmt::skip]
+        let stream = serde_json::json!({
+            "index": 1, "codec_name": codec, "codec_type": "audio", "channels": 6, "start_pts": 0,
+            "disposition": { "forced": 0 }, "tags": { "language": "jpn" },
+        });
+        Probe { stream: serde_json::from_value(stream).unwrap(), file: PathBuf::from("/tmp/Show - 01.mkv"), offset: 0, index: None, src2: false }
+    }
+
+    // directory under the system temp directory for a single test, removed with everything in it when dropped
+    pub(crate) struct ScratchDir(pub(crate) PathBuf);
+
+    impl ScratchDir {
+        pub(crate) fn new(name: &str) -> ScratchDir {
+            let dir = env::temp_dir().join(format!("grav1an-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            ScratchDir(dir)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // only parsed, the generated scripts import plugins that don't have to be installed. Skipped without python3
+    fn assert_valid_python(script: &str) {
+        if which("python3").is_err() {
+            eprintln!("python3 isn't installed, not checking that the script parses");
+            return;
+        }
+        let mut python = Command::new("python3")
+            .args(["-c", "import ast, sys; ast.parse(sys.stdin.read())"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("python3 is needed to check the generated scripts");
+        python.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+        assert!(python.wait().unwrap().success(), "not valid python:\n{script}");
+    }
+
+    #[test]
+    fn source_string_quotes_apostrophes() {
+        let file = PathBuf::from("/tmp/it's a show/01.mkv");
+        for filter in ["lsmash", "bestsource", "dgdecnv"] {
+            let source = get_source_string(&file, &test_args(&["--source_filter", filter]), Some("yuv420p10".to_string()));
+            assert!(source.contains(r#""/tmp/it's a show/01.mkv""#), "{source}");
+            assert_valid_python(&format!("src = {source}\n"));
+        }
+    }
+
+    #[test]
+    fn ffprobe_json_with_large_start_pts() {
+        let json = r#"{"streams": [
+            {"index": 0, "codec_name": "h264", "codec_type": "video", "avg_frame_rate": "24000/1001", "start_pts": 900000, "time_base": "1/90000",
+             "width": 1920, "height": 1080, "pix_fmt": "yuv420p", "disposition": {"forced": 0}, "tags": {"language": "jpn"}},
+            {"index": 300, "codec_name": "ac3", "codec_type": "audio", "channels": 6, "start_pts": 900000, "time_base": "1/48000",
+             "disposition": {"forced": 0}, "tags": {}}
+        ]}"#;
+        let probe: FileProbe = serde_json::from_str(json).unwrap();
+        let [video, audio] = [0, 1].map(|i| Probe { stream: probe.streams[i].clone(), file: PathBuf::new(), offset: 0, index: None, src2: false });
+        assert_eq!(video.stream.start_pts, 900000);
+        assert_eq!(video.start_ms(), 10000);
+        assert_eq!(audio.stream.index, 300);
+        assert_eq!(audio.start_ms(), 18750);
+    }
+
+    #[test]
+    fn src2_only_from_the_matching_episode() {
+        let scratch = ScratchDir::new("src2");
+        let dir = &scratch.0;
+        for episode in ["01", "02"] {
+            File::create(dir.join(format!("Show - {episode} [BD].mkv"))).unwrap();
+        }
+        let args = test_args(&["--src2-directory", path_str(&dir).as_str(), "--audio", "2"]);
+        let matched = matched_src2(&PathBuf::from("/tmp/Show - 01.mkv"), &"01".to_string(), &args);
+        assert_eq!(matched, Some(vec![dir.join("Show - 01 [BD].mkv")]));
+    }
+
+    #[test]
+    fn merge_script_passes_match_the_grain_string() {
+        let args = test_args(&["--diff-grain", "--lehmer-merge", "--merge-passes", "4", "--src2-directory", "/tmp"]);
+        let scratch = ScratchDir::new("merge");
+        let script = scratch.0.join("merge.vpy");
+        merge_script(&script, &args, &vec![test_video("/tmp/Show - 01.mkv"), test_video("/tmp/bd/Show - 01.mkv")]);
+        let content = fs::read_to_string(&script).unwrap();
+        let passes = Regex::new(r"passes=(\d+)").unwrap();
+        let [written, reported] = [content.as_str(), get_grain_string(&args).as_str()].map(|s| passes.captures(s).unwrap().unwrap()[1].to_string());
+        assert_eq!(written, "4");
+        assert_eq!(written, reported);
+    }
+
+    #[test]
+    fn pcm_wins_over_ac3() {
+        let args = test_args(&[]);
+        for (first, second) in [("pcm_s24le", "ac3"), ("ac3", "pcm_s24le")] {
+            let picked = compare_streams(test_audio(first), test_audio(second), &args);
+            assert_eq!(picked.stream.codec_name, "pcm_s24le");
+        }
+    }
+
+    #[test]
+    fn q_smooth_flattens_spikes_within_the_range() {
+        let raw = [30., 30., 50., 30., 30., 30., 10., 30., 30.];
+        let q_range = [20., 40.];
+        let smoothed = smooth_quantizers(&raw, 2, q_range);
+        let largest_step = |qs: &[f32]| qs.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0f32, f32::max);
+        assert_eq!(smoothed.len(), raw.len());
+        assert!(largest_step(&smoothed) <= largest_step(&raw) / 2., "{smoothed:?}");
+        assert!(smoothed.iter().all(|q| (q_range[0]..=q_range[1]).contains(q)), "{smoothed:?}");
+    }
+
+    #[test]
+    fn filter_script_is_valid_python_with_and_without_deband() {
+        let scratch = ScratchDir::new("filter");
+        let script = scratch.0.join("filter.vpy");
+        let file = PathBuf::from("/tmp/Show - 01.mkv");
+        for extra in [&[][..], &["--no-deband"][..]] {
+            let args = test_args(extra);
+            create_vpy_script(&script, &file, &args, &vec![test_video("/tmp/Show - 01.mkv")]);
+            let content = fs::read_to_string(&script).unwrap();
+            assert_valid_python(&content);
+            assert_eq!(content.contains("deband = "), !args.no_deband, "{content}");
+        }
+    }
+
+    #[test]
+    fn filter_string_quotes_are_balanced() {
+        let flags = ["--no-denoise", "--dehalo", "--retinex", "--no-deband", "--ref-calc"];
+        for combination in flags.iter().powerset() {
+            // --retinex is part of the deband, clap rejects it together with --no-deband
+            let Ok(args) = Args::try_parse_from(["grav1an", "-i", "/tmp", "-o", "/tmp/out", "-n", "Show"].iter().chain(combination.iter().copied())) else {
+                continue;
+            };
+            let filters = get_filter_string(&args);
+            assert_eq!(filters.matches('"').count() % 2, 0, "{combination:?}: {filters}");
+            assert!(!filters.contains(",,") && !filters.contains(", ,"), "{combination:?}: {filters}");
+        }
+    }
+
+    #[test]
+    fn measured_7_1_track_gets_320k() {
+        assert_eq!(opus_bitrate(8, 0), Some("320"));
+        assert_eq!(opus_bitrate(8, 4_500_000), Some("320"));
+        assert_eq!(opus_bitrate(8, 300_000), None);
+    }
+
+    #[test]
+    fn only_cd_and_part_suffixes_are_split() {
+        assert_eq!(split_part(&PathBuf::from("/tmp/Movie CD1.mkv")), Some(("Movie".to_string(), 1)));
+        assert_eq!(split_part(&PathBuf::from("/tmp/Show - 05.part2.mkv")), Some(("Show - 05".to_string(), 2)));
+        assert_eq!(split_part(&PathBuf::from("/tmp/Deathly Hallows Part 1.mkv")), None);
+        assert_eq!(split_part(&PathBuf::from("/tmp/Deathly Hallows Part 2.mkv")), None);
+    }
+
+    #[test]
+    fn rav1e_quantizers_above_127_arent_clamped() {
+        let args = test_args(&["--encoder", "rav1e"]);
+        assert_eq!(round_quantizer(150.6, &args), 150.);
+        assert_eq!(round_quantizer(40.2, &args), 40.);
     }
 }
@@ -0,0 +1,74 @@
+use super::Args;
+#[cfg(feature = "metadata")]
+use serde_json::{json, Value};
+#[cfg(feature = "metadata")]
+use std::time::Duration;
+
+// Fills in the series title once per batch, --name is kept whenever the lookup fails
+pub fn apply(args: &mut Args) {
+    if args.anilist_id.is_none() && args.tvdb_id.is_none() {
+        return;
+    }
+    args.series_title = series_title(args);
+}
+
+#[cfg(not(feature = "metadata"))]
+fn series_title(_args: &Args) -> Option<String> {
+    eprintln_log!("WARNING: grav1an was built without the metadata feature, using --name for the series title");
+    None
+}
+
+#[cfg(feature = "metadata")]
+fn series_title(args: &Args) -> Option<String> {
+    let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build();
+    let title = if let Some(id) = args.anilist_id {
+        anilist_title(&agent, id)
+    } else {
+        tvdb_title(&agent, args.tvdb_id.unwrap(), args.tvdb_api_key.as_ref().unwrap())
+    };
+    if let Err(e) = &title {
+        eprintln_log!("WARNING: Failed to fetch the series title, using --name instead: {e}");
+    }
+    let title = title.ok()?;
+    println_log!("Series title: {title}");
+    Some(title)
+}
+
+#[cfg(feature = "metadata")]
+fn anilist_title(agent: &ureq::Agent, id: u32) -> Result<String, String> {
+    let query = "query ($id: Int) { Media (id: $id, type: ANIME) { title { romaji english } } }";
+    let response: Value = agent
+        .post("https://graphql.anilist.co")
+        .send_json(json!({ "query": query, "variables": { "id": id } }))
+        .map_err(|e| e.to_string())?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+    let title = &response["data"]["Media"]["title"];
+    title["romaji"]
+        .as_str()
+        .or(title["english"].as_str())
+        .map(|t| t.to_string())
+        .ok_or(format!("AniList has no title for {id}"))
+}
+
+#[cfg(feature = "metadata")]
+fn tvdb_title(agent: &ureq::Agent, id: u32, api_key: &String) -> Result<String, String> {
+    let login: Value = agent
+        .post("https://api4.thetvdb.com/v4/login")
+        .send_json(json!({ "apikey": api_key }))
+        .map_err(|e| e.to_string())?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+    let token = login["data"]["token"].as_str().ok_or("TVDB login failed".to_string())?;
+    let series: Value = agent
+        .get(format!("https://api4.thetvdb.com/v4/series/{id}").as_str())
+        .set("Authorization", format!("Bearer {token}").as_str())
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+    series["data"]["name"]
+        .as_str()
+        .map(|t| t.to_string())
+        .ok_or(format!("TVDB has no title for {id}"))
+}
@@ -1,16 +1,19 @@
-use av_metrics_decoders::{Decoder, VapoursynthDecoder};
+use super::{confirm, logging, path_str, py_path, vs_plugins, Args};
+use av_metrics_decoders::{Decoder, Frame as VideoFrame, VapoursynthDecoder};
 use vapoursynth::core::CoreRef;
 use vapoursynth::prelude::*;
 use crossterm::tty::IsTty;
-use indicatif::{HumanDuration, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
 use ssimulacra2::{
     ColorPrimaries as Primaries, MatrixCoefficients as Matrices,
     TransferCharacteristic as Transfers, *,
 };
 use std::cmp::min;
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::{stderr, prelude::*};
 use std::path::{absolute as abs, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::available_parallelism;
 use std::time::Duration;
@@ -70,7 +73,7 @@ fn to_primaries(input: String) -> Primaries {
 
 fn to_matrices(input: String) -> Matrices {
     match input.as_str() {
-        "rgb" => Matrices::Identity,
+        "rgb" | "gbr" => Matrices::Identity,
         "bt709" => Matrices::BT709,
         "fcc" => Matrices::BT470M,
         "bt470bg" => Matrices::BT470BG,
@@ -110,6 +113,56 @@ fn to_transfers(input: String) -> Transfers {
 }
 
 const PROGRESS_CHARS: &str = "█▉▊▋▌▍▎▏  ";
+const ASCII_PROGRESS_CHARS: &str = "#>-";
+const ASCII_PROGRESS_TEMPLATE: &str = "{elapsed_precise:.bold} [{wide_bar:.blue/white.dim}] {percent:.bold}  {pos} ({fps:.bold}, eta {fixed_eta}{msg})";
+const ASCII_SPINNER_TEMPLATE: &str = "{elapsed_precise:.bold} {pos} ({fps:.bold}{msg})";
+const ASCII_BATCH_TEMPLATE: &str = "{elapsed_precise:.bold} [{wide_bar:.blue/white.dim}] {percent:.bold}  {pos} episodes (eta {fixed_eta}{msg})";
+static ASCII_PROGRESS: AtomicBool = AtomicBool::new(false);
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+
+// legacy consoles draw the block characters as garbage, so they get plain ASCII bars instead
+pub fn set_progress_style(args: &Args) {
+    let ascii = args.ascii_progress || !stderr().is_tty() || !utf8_console();
+    ASCII_PROGRESS.store(ascii, Ordering::Relaxed);
+    let no_color = args.no_color || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    NO_COLOR.store(no_color, Ordering::Relaxed);
+}
+
+fn utf8_console() -> bool {
+    if cfg!(windows) {
+        // Windows Terminal renders UTF-8, conhost with a legacy code page doesn't
+        return std::env::var_os("WT_SESSION").is_some();
+    }
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"].iter().filter_map(|v| std::env::var(v).ok()).find(|v| !v.is_empty());
+    locale.map_or(true, |l| l.to_lowercase().replace('-', "").contains("utf8"))
+}
+
+fn ascii_progress() -> bool {
+    ASCII_PROGRESS.load(Ordering::Relaxed)
+}
+
+// with colors off the .style part of every {key:spec.style} is dropped, alignment and width stay
+fn styled(template: &str) -> String {
+    if !NO_COLOR.load(Ordering::Relaxed) {
+        return template.to_string();
+    }
+    let (mut plain, mut in_key, mut in_spec, mut in_style) = (String::new(), false, false, false);
+    for c in template.chars() {
+        if c == '{' {
+            in_key = true;
+        } else if c == '}' {
+            (in_key, in_spec, in_style) = (false, false, false);
+        } else if c == ':' && in_key {
+            in_spec = true;
+        } else if c == '.' && in_spec {
+            in_style = true;
+        }
+        if !in_style {
+            plain.push(c);
+        }
+    }
+    plain.replace(":}", "}")
+}
 const INDICATIF_PROGRESS_TEMPLATE: &str = if cfg!(windows) {
     // Do not use a spinner on Windows since the default console cannot display
     // the characters used for the spinner
@@ -127,8 +180,9 @@ const INDICATIF_SPINNER_TEMPLATE: &str = if cfg!(windows) {
 };
 
 fn pretty_progress_style() -> ProgressStyle {
+    let template = if ascii_progress() { ASCII_PROGRESS_TEMPLATE } else { INDICATIF_PROGRESS_TEMPLATE };
     ProgressStyle::default_bar()
-        .template(INDICATIF_PROGRESS_TEMPLATE)
+        .template(&styled(template))
         .unwrap()
         .with_key(
             "fps",
@@ -174,12 +228,24 @@ fn pretty_progress_style() -> ProgressStyle {
                 write!(w, "{:>3.0}%", state.fraction() * 100_f32).unwrap();
             },
         )
-        .progress_chars(PROGRESS_CHARS)
+        .progress_chars(if ascii_progress() { ASCII_PROGRESS_CHARS } else { PROGRESS_CHARS })
+}
+
+const INDICATIF_BATCH_TEMPLATE: &str = "{elapsed_precise:.bold} ▕{wide_bar:.blue/white.dim}▏ {percent:.bold}  {pos} episodes (eta {fixed_eta}{msg})";
+
+pub fn batch_progress(episodes: u64) -> ProgressBar {
+    if !stderr().is_tty() {
+        return ProgressBar::hidden();
+    }
+    let template = if ascii_progress() { ASCII_BATCH_TEMPLATE } else { INDICATIF_BATCH_TEMPLATE };
+    let style = pretty_progress_style().template(&styled(template)).unwrap();
+    logging::add_bar(ProgressBar::new(episodes).with_style(style))
 }
 
 fn pretty_spinner_style() -> ProgressStyle {
+    let template = if ascii_progress() { ASCII_SPINNER_TEMPLATE } else { INDICATIF_SPINNER_TEMPLATE };
     ProgressStyle::default_bar()
-        .template(INDICATIF_SPINNER_TEMPLATE)
+        .template(&styled(template))
         .unwrap()
         .with_key(
             "fps",
@@ -202,7 +268,7 @@ fn pretty_spinner_style() -> ProgressStyle {
                 write!(w, "{}", state.pos()).unwrap();
             },
         )
-        .progress_chars(PROGRESS_CHARS)
+        .progress_chars(if ascii_progress() { ASCII_PROGRESS_CHARS } else { PROGRESS_CHARS })
 }
 
 fn calc_score<S: Pixel, D: Pixel, E: Decoder, F: Decoder>(
@@ -210,9 +276,8 @@ fn calc_score<S: Pixel, D: Pixel, E: Decoder, F: Decoder>(
     src_yuvcfg: &YuvConfig,
     dst_yuvcfg: &YuvConfig,
     inc: usize,
-    verbose: bool,
-) -> Option<(usize, f64)> {
-    let (frame_idx, (src_frame, dst_frame)) = {
+) -> Option<(usize, f64, usize)> {
+    let (frame_idx, skipped, (src_frame, dst_frame)) = {
         let mut guard = mtx.lock().unwrap();
         let curr_frame = guard.0;
 
@@ -221,38 +286,58 @@ fn calc_score<S: Pixel, D: Pixel, E: Decoder, F: Decoder>(
 
         if let (Some(sf), Some(df)) = (src_frame, dst_frame) {
             // skip remaining frames in increment size
-            for ii in 1..inc {
+            let mut skipped = 0;
+            for _ in 1..inc {
                 let _src_frame = guard.1 .0.read_video_frame::<S>();
                 let _dst_frame = guard.1 .1.read_video_frame::<D>();
                 if _src_frame.is_none() || _dst_frame.is_none() {
                     break;
                 }
-                if verbose {
-                    println!("Frame {}: skip", curr_frame + ii);
-                }
+                skipped += 1;
             }
 
             guard.0 += inc;
-            (curr_frame, (sf, df))
+            (curr_frame, skipped, (sf, df))
         } else {
             return None;
         }
     };
 
-    let src_yuv = Yuv::new(src_frame, *src_yuvcfg).unwrap();
-    let dst_yuv = Yuv::new(dst_frame, *dst_yuvcfg).unwrap();
+    let src_rgb = to_linear_rgb(src_frame, src_yuvcfg);
+    let dst_rgb = to_linear_rgb(dst_frame, dst_yuvcfg);
 
     Some((
         frame_idx,
-        compute_frame_ssimulacra2(src_yuv, dst_yuv).expect("Failed to calculate ssimulacra2"),
+        compute_frame_ssimulacra2(src_rgb, dst_rgb).expect("Failed to calculate ssimulacra2"),
+        skipped,
     ))
 }
 
+// yuvxyb decodes the identity matrix as YCbCr, so the planes are read directly as RGB instead.
+// Every frame comes from VapoursynthDecoder and VapourSynth's RGB formats store R, G and B in that order
+fn to_linear_rgb<T: Pixel>(frame: VideoFrame<T>, config: &YuvConfig) -> LinearRgb {
+    if config.matrix_coefficients != Matrices::Identity {
+        return LinearRgb::try_from(Yuv::new(frame, *config).unwrap()).unwrap();
+    }
+    let [r, g, b] = &frame.planes;
+    let (width, height) = (r.cfg.width, r.cfg.height);
+    let (offset, scale) = if config.full_range {
+        (0f32, ((1u32 << config.bit_depth) - 1) as f32)
+    } else {
+        ((16u32 << (config.bit_depth - 8)) as f32, (219u32 << (config.bit_depth - 8)) as f32)
+    };
+    let data = r.rows_iter().zip(g.rows_iter()).zip(b.rows_iter()).flat_map(|((r, g), b)| {
+        (0..width).map(move |x| [r[x], g[x], b[x]].map(|v| (Into::<u32>::into(v) as f32 - offset) / scale))
+    }).collect();
+    let rgb = Rgb::new(data, width, height, config.transfer_characteristics, config.color_primaries).unwrap();
+    LinearRgb::try_from(rgb).unwrap()
+}
+
 fn lwlibavsource<'a>(file: &PathBuf, api: &API, core: &CoreRef<'a>, format: &str) -> Node<'a> {
     let lsmas = core.get_plugin_by_namespace("lsmas").unwrap().expect("Failed to find lsmas namespace! Is the plugin installed?");
     let mut args = OwnedMap::new(*api);
-    args.set_data("source", file.to_str().unwrap().as_bytes()).unwrap();
-    args.set_data("cachedir", file.parent().unwrap().to_str().unwrap().as_bytes()).unwrap();
+    args.set_data("source", path_str(&file).as_bytes()).unwrap();
+    args.set_data("cachedir", path_str(&file.parent().unwrap()).as_bytes()).unwrap();
     args.set_data("format", format.as_bytes()).unwrap();
     args.set_int("prefer_hw", 3).unwrap();
     let func = lsmas.invoke("LWLibavSource", &args).unwrap();
@@ -270,7 +355,7 @@ fn bestsource<'a>(file: &PathBuf, api: &API, core: &CoreRef<'a>) -> Node<'a> {
         root.push('/');
     }
     let mut args = OwnedMap::new(*api);
-    args.set_data("source", abs(file).unwrap().to_str().unwrap().as_bytes()).unwrap();
+    args.set_data("source", path_str(&abs(file).unwrap()).as_bytes()).unwrap();
     args.set_data("cachepath", root.as_bytes()).unwrap();
     let func = bs.invoke("VideoSource", &args).unwrap();
     if func.error().is_some() {
@@ -282,7 +367,7 @@ fn bestsource<'a>(file: &PathBuf, api: &API, core: &CoreRef<'a>) -> Node<'a> {
 fn dgdecodenv<'a>(file: &PathBuf, api: &API, core: &CoreRef<'a>) -> Node<'a> {
     let dgdecodenv = core.get_plugin_by_namespace("dgdecodenv").unwrap().expect("Failed to find dgdecodenv namespace! Is the plugin installed?");
     let mut args = OwnedMap::new(*api);
-    args.set_data("source", file.to_str().unwrap().as_bytes()).unwrap();
+    args.set_data("source", path_str(&file).as_bytes()).unwrap();
     let func = dgdecodenv.invoke("DGSource", &args).unwrap();
     if func.error().is_some() {
         panic!("{}", func.error().unwrap());
@@ -290,10 +375,44 @@ fn dgdecodenv<'a>(file: &PathBuf, api: &API, core: &CoreRef<'a>) -> Node<'a> {
     func.get_node("clip").unwrap()
 }
 
-pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &String) -> BTreeMap<usize, f64> {
-    let threads = available_parallelism().unwrap().get();
+// mean absolute difference between the source and its denoised self in 8 bit levels, for every measured frame of a _grain.vpy
+pub fn get_grain_levels(script: &PathBuf, cycle: u8) -> BTreeMap<usize, f64> {
+    let environment = Environment::from_file(script, EvalFlags::Nothing).unwrap();
+    let (node, _) = environment.get_output(0).unwrap();
+    let frames = node.info().num_frames;
+    let progress = if stderr().is_tty() {
+        let pb = ProgressBar::new(frames as u64).with_style(pretty_progress_style());
+        let pb = logging::add_bar(pb);
+        pb
+    } else {
+        ProgressBar::hidden()
+    };
+    let mut levels = BTreeMap::new();
+    for n in 0..frames {
+        let frame = node.get_frame(n).expect("Failed to generate frame!");
+        let diff = frame.props().get_float("PlaneStatsDiff").expect("Failed to get PlaneStatsDiff!");
+        levels.insert(n * cycle as usize, diff * 255.);
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+    levels
+}
+
+pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, args: &Args) -> BTreeMap<usize, f64> {
+    let (cycle, algo, verbose) = (args.cycle, &args.source_filter, args.verbose);
+    let interactive = !args.non_interactive && std::io::stdin().is_tty();
+    let threads = args.vs_threads.map(|t| t as usize).unwrap_or(available_parallelism().unwrap().get());
     let api = API::get().unwrap();
     let core = api.create_core(threads as i32);
+    let std = core.get_plugin_by_namespace("std").unwrap().unwrap();
+    for plugin in vs_plugins(args) {
+        let mut plugin_args = OwnedMap::new(api);
+        plugin_args.set_data("path", path_str(&plugin).as_bytes()).unwrap();
+        let loaded = std.invoke("LoadPlugin", &plugin_args).unwrap();
+        if loaded.error().is_some() {
+            panic!("{}", loaded.error().unwrap());
+        }
+    }
     let vszip = core.get_plugin_by_namespace("vszip").unwrap().expect("Failed to find vszip namespace! Is the plugin installed?");
     let skip_content = if src.extension().is_some_and(|e| e.to_ascii_lowercase() == "vpy") {
         Some(Environment::from_file(src, EvalFlags::Nothing).unwrap())
@@ -322,7 +441,7 @@ pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &Strin
     } else {
         unreachable!()
     };
-    let frames = reference.info().num_frames;
+    let frames = (0..reference.info().num_frames).step_by(sample_stride(Some(reference.info().num_frames), args.metric_sample, args.metric_stride)).collect::<Vec<usize>>();
     let mut args = OwnedMap::new(api);
     args.set_node("reference", &reference).unwrap();
     args.set_node("distorted", &distort).unwrap();
@@ -333,10 +452,10 @@ pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &Strin
     }
     let scored_node = scored.get_node("clip").unwrap();
     let progress = if stderr().is_tty() {
-        let pb = ProgressBar::new(frames as u64)
+        let pb = ProgressBar::new(frames.len() as u64)
             .with_style(pretty_progress_style())
             .with_message(", avg: N/A");
-        pb.set_draw_target(ProgressDrawTarget::stderr());
+        let pb = logging::add_bar(pb);
         pb.enable_steady_tick(Duration::from_millis(100));
         pb.reset();
         pb.reset_eta();
@@ -349,7 +468,7 @@ pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &Strin
     let mut avg = 0f64;
     let mut results = BTreeMap::new();
     let mut jobs = 0u8;
-    for index in 0..frames {
+    for &index in &frames {
         loop {
             if (jobs as usize) < threads {
                 break;
@@ -363,7 +482,7 @@ pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &Strin
             results.insert(n * cycle as usize, score);
             avg = avg + (score - avg) / (min(results.len(), 10) as f64);
             if progress.is_finished() {
-                eprintln!("Got frame but progress bar is finished! Frame: {n}");
+                eprintln_log!("Got frame but progress bar is finished! Frame: {n}");
             } else {
                 progress.set_message(format!(", avg: {:.1$}", avg, 2));
                 progress.inc(1);
@@ -372,47 +491,101 @@ pub fn get_vs_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &Strin
         });
     }
     loop {
-        if results.len() >= frames {
+        if results.len() >= frames.len() {
             progress.finish();
             break;
         }
         if jobs == 0 {
-            eprintln!("Number of SSIMULACRA2 jobs has reached 0, but results only has {} entries instead of {frames}!", results.len());
-            println!("PAUSED: Would you like to continue?");
-            print!("(yes/no): ");
-            std::io::stdout().flush().expect("Failed to flush!");
-            let mut input: String = String::new();
-            std::io::stdin().read_line(&mut input).expect("Failed to read input!");
-            if input != "yes\n" {
-                eprintln!("\nAborted. Exiting script.");
+            eprintln_log!("Number of SSIMULACRA2 jobs has reached 0, but results only has {} entries instead of {}!", results.len(), frames.len());
+            if !interactive {
+                // nobody is there to answer the prompt, so the missing frames get one synchronous retry
+                let missing: Vec<usize> = frames.iter().copied().filter(|n| !results.contains_key(&(n * cycle as usize))).collect();
+                for index in missing {
+                    let Ok(frame) = scored_node.get_frame(index) else {
+                        eprintln_log!("Failed to score frame {index} again, exiting.");
+                        exit(1);
+                    };
+                    let score = frame.props().get_float("_SSIMULACRA2").expect("Failed to get SSIMULACRA2 score!");
+                    results.insert(index * cycle as usize, score);
+                    progress.inc(1);
+                }
+                break;
+            }
+            if !confirm("Would you like to continue?", None) {
+                eprintln_log!("\nAborted. Exiting script.");
                 exit(0);
             }
-            println!("Continuing.");
+            println_log!("Continuing.");
             break;
         }
         std::thread::sleep(Duration::from_millis(100));
     }
     progress.finish();
+    if verbose {
+        let total = reference.info().num_frames;
+        println_log!("Scored {} frames of {}, skipped {}", results.len(), distorted.display(), total.saturating_sub(results.len()));
+    }
     results
 }
 
-pub fn get_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, cr: String, matrix: String, transfer: String, primaries: String) -> BTreeMap<usize, f64> {
-    let threads = available_parallelism().unwrap().get() / 2usize;
-    let skip_content = if src.extension().is_some_and(|e| e.to_ascii_lowercase() == "vpy") {
-        VapoursynthDecoder::new_from_script(&src).unwrap()
-    } else {
-        VapoursynthDecoder::new_from_video(&src).unwrap()
+// width, height, frame count, frame rate and format name of a script's first output
+pub fn script_info(file: &PathBuf) -> (usize, usize, usize, (u64, u64), String) {
+    let environment = Environment::from_file(file, EvalFlags::SetWorkingDir).unwrap_or_else(|e| panic!("Failed to evaluate {}: {e}", file.display()));
+    let node = environment.get_output(0).expect("The script has no output at index 0!").0;
+    let info = node.info();
+    let (Property::Constant(format), Property::Constant(resolution), Property::Constant(framerate)) = (info.format, info.resolution, info.framerate) else {
+        panic!("{} has a variable format, resolution or frame rate!", file.display());
     };
-    println!("{}", distorted.display());
-    let distort_content = VapoursynthDecoder::new_from_video(&distorted).unwrap();
+    (resolution.width, resolution.height, info.num_frames, (framerate.numerator, framerate.denominator), format.name().to_string())
+}
+
+// VapoursynthDecoder::new_from_video doesn't go through DGDecodeNV, so wrap the file in a DGSource script
+fn dgdecodenv_script(file: &PathBuf) -> PathBuf {
+    let script = file.with_extension("dgsource.vpy");
+    if script.try_exists().is_ok_and(|b| b == false) {
+        let contents = format!("import vapoursynth as vs\ncore = vs.core\nsrc = core.dgdecodenv.DGSource({})\nsrc.set_output(0)\n", py_path(&abs(file).unwrap()));
+        File::create(&script).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+    script
+}
+
+// decoded frames between two scored ones, --metric-sample spreads its frames evenly over the clip
+// the stride counts frames of the already decimated clip, so it stacks with --cycle instead of replacing it
+fn sample_stride(frames: Option<usize>, sample: Option<u32>, stride: u16) -> usize {
+    if let (Some(frames), Some(sample)) = (frames, sample) {
+        frames.div_ceil(sample as usize).max(1)
+    } else {
+        if sample.is_some() {
+            eprintln_log!("WARNING: Couldn't determine the frame count, using --metric-stride {stride}");
+        }
+        stride as usize
+    }
+}
+
+fn vapoursynth_decoder(file: &PathBuf, algo: &String) -> VapoursynthDecoder {
+    if file.extension().is_some_and(|e| e.to_ascii_lowercase() == "vpy") {
+        VapoursynthDecoder::new_from_script(&file).unwrap()
+    } else if algo == "dgdecnv" {
+        VapoursynthDecoder::new_from_script(&dgdecodenv_script(&file)).unwrap()
+    } else {
+        VapoursynthDecoder::new_from_video(&file).unwrap()
+    }
+}
+
+pub fn get_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, algo: &String, sample: Option<u32>, stride: u16, cr: String, matrix: String, transfer: String, primaries: String, verbose: bool) -> BTreeMap<usize, f64> {
+    let threads = available_parallelism().unwrap().get() / 2usize;
+    let skip_content = vapoursynth_decoder(&src, algo);
+    println_log!("{}", distorted.display());
+    let distort_content = vapoursynth_decoder(&distorted, algo);
     let distort_frames = distort_content.get_frame_count().ok();
     let total_frames = skip_content.get_frame_count().ok();
     if distort_frames.is_some()
         && total_frames.is_some()
         && (distort_frames.unwrap() != total_frames.unwrap())
     {
-        eprintln!("WARNING: Frame count mismatch detected, scores may be inaccurate");
+        eprintln_log!("WARNING: Frame count mismatch detected, scores may be inaccurate");
     }
+    let inc = sample_stride(total_frames.or(distort_frames), sample, stride);
     let src_info = skip_content.get_video_details();
     let distort_info = distort_content.get_video_details();
     let src_ss = src_info.chroma_sampling.get_decimation().unwrap_or((0, 0));
@@ -436,6 +609,13 @@ pub fn get_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, cr: String, mat
     dst_config.bit_depth = distort_info.bit_depth as u8;
     dst_config.subsampling_x = dist_ss.0 as u8;
     dst_config.subsampling_y = dist_ss.1 as u8;
+    if matrices == Matrices::Identity && dist_ss != (0, 0) {
+        // a subsampled encode of an RGB source can't be GBR, so it gets the matrix its size implies
+        dst_config.matrix_coefficients = Matrices::from_size(width, height, None);
+        eprintln_log!("WARNING: RGB source compared against a subsampled encode, assuming {:?} for the encode", dst_config.matrix_coefficients);
+    } else if matrices == Matrices::Identity && src_ss != (0, 0) {
+        panic!("{} is tagged as RGB but is chroma subsampled", src.display());
+    }
     let (result_tx, result_rx) = mpsc::channel();
     let current_frame = 0usize;
     let decoders = Arc::new(Mutex::new((current_frame, (skip_content, distort_content))));
@@ -449,29 +629,25 @@ pub fn get_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, cr: String, mat
                         &decoders,
                         &src_config,
                         &dst_config,
-                        1,
-                        false,
+                        inc,
                     ),
                     (8, _) => calc_score::<u8, u16, _, _>(
                         &decoders,
                         &src_config,
                         &dst_config,
-                        1,
-                        false,
+                        inc,
                     ),
                     (_, 8) => calc_score::<u16, u8, _, _>(
                         &decoders,
                         &src_config,
                         &dst_config,
-                        1,
-                        false,
+                        inc,
                     ),
                     (_, _) => calc_score::<u16, u16, _, _>(
                         &decoders,
                         &src_config,
                         &dst_config,
-                        1,
-                        false,
+                        inc,
                     ),
                 };
 
@@ -487,13 +663,13 @@ pub fn get_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, cr: String, mat
     let progress = if stderr().is_tty() {
         let frame_count = total_frames.or(distort_frames);
         let pb = if let Some(frame_count) = frame_count {
-            ProgressBar::new(frame_count as u64)
+            ProgressBar::new(frame_count.div_ceil(inc) as u64)
                 .with_style(pretty_progress_style())
                 .with_message(", avg: N/A")
         } else {
             ProgressBar::new_spinner().with_style(pretty_spinner_style())
         };
-        pb.set_draw_target(ProgressDrawTarget::stderr());
+        let pb = logging::add_bar(pb);
         pb.enable_steady_tick(Duration::from_millis(100));
         pb.reset();
         pb.reset_eta();
@@ -505,12 +681,20 @@ pub fn get_ssimu2(src: &PathBuf, distorted: &PathBuf, cycle: u8, cr: String, mat
     };
     let mut results = BTreeMap::new();
     let mut avg = 0f64;
+    let mut skipped = 0usize;
     for score in result_rx {
         results.insert(score.0 * cycle as usize, score.1);
+        skipped += score.2;
         avg = avg + (score.1 - avg) / (min(results.len(), 10) as f64);
         progress.set_message(format!(", avg: {:.1$}", avg, 2));
         progress.inc(1);
+        if verbose && results.len() % 1000 == 0 {
+            println_log!("Scored {} frames, skipped {skipped}", results.len());
+        }
     }
     progress.finish();
+    if verbose {
+        println_log!("Scored {} frames of {}, skipped {skipped} (1 in {inc} scored)", results.len(), distorted.display());
+    }
     results
 }
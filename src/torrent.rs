@@ -1,12 +1,22 @@
-use super::{get_encoder_version, get_filter_string, get_grain_string, get_rescale_string, Args};
+use super::{get_encoder_version, path_str, Args, ReleaseInfo};
 use core::str;
 use lava_torrent::bencode::BencodeElem::{Integer as bInt, String as bString};
-use lava_torrent::torrent::v1::TorrentBuilder;
+use lava_torrent::torrent::v1::{Torrent, TorrentBuilder};
+use std::fs;
 use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// total size of a file or of everything below a folder
+fn total_size(path: &PathBuf) -> u64 {
+    if path.is_dir() {
+        fs::read_dir(path).unwrap().map(|entry| total_size(&entry.unwrap().path())).sum()
+    } else {
+        path.metadata().unwrap().len()
+    }
+}
+
 fn pieces(file: &PathBuf) -> u64 {
-    let size = file.metadata().unwrap().len();
+    let size = total_size(file);
     let min_size = 16u64 * 1024u64; // 16 KB
     let max_size = 16u64 * 1024u64 * 1024u64; // 16 MB
     let max_pieces = if size <= 2u64.pow(30) {
@@ -22,9 +32,53 @@ fn pieces(file: &PathBuf) -> u64 {
     2u64.pow(exponent).clamp(min_size, max_size)
 }
 
+// hard links (or copies across file systems) the --torrent-include files next to the output,
+// a single output gets moved into a folder of the same name so the torrent becomes multi-file
+fn stage_torrent_files(torrent_files: &PathBuf, args: &Args) -> PathBuf {
+    if args.torrent_include.is_empty() {
+        return torrent_files.clone();
+    }
+    let folder = if torrent_files.is_dir() {
+        torrent_files.clone()
+    } else {
+        torrent_files.with_extension("")
+    };
+    fs::create_dir_all(&folder).unwrap();
+    let mut files = args.torrent_include.clone();
+    if torrent_files.is_file() {
+        files.push(torrent_files.clone());
+    }
+    for file in files {
+        let staged = folder.join(file.file_name().unwrap());
+        if staged.exists() {
+            continue;
+        }
+        if fs::hard_link(&file, &staged).is_err() {
+            fs::copy(&file, &staged).unwrap_or_else(|e| panic!("Failed to copy {} into {}: {e}", file.display(), folder.display()));
+        }
+    }
+    folder
+}
+
+// reads the written torrent back and compares its pieces to a fresh hash of the files
+fn verify_torrent(torrent_path: &PathBuf, torrent_files: &PathBuf) {
+    let written = Torrent::read_from_file(torrent_path)
+        .unwrap_or_else(|e| panic!("Failed to read back {}: {e}", torrent_path.display()));
+    let rehashed = TorrentBuilder::new(torrent_files, written.piece_length).build().unwrap();
+    if written.pieces != rehashed.pieces {
+        let _ = fs::remove_file(torrent_path);
+        panic!(
+            "{} changed while its torrent was made, removed {}",
+            torrent_files.display(),
+            torrent_path.display()
+        );
+    }
+    println_log!("Verified {} against {}", torrent_path.display(), torrent_files.display());
+}
+
 pub fn create_torrent(
     opus_options: String,
-    encoder_options: String,
+    release_info: &ReleaseInfo,
     torrent_path: &PathBuf,
     torrent_files: &PathBuf,
     args: &Args,
@@ -35,37 +89,39 @@ pub fn create_torrent(
     } else {
         comment_string = "AV1 encode with some filters\n".into();
     }
-    if !args.single_pass {
-        comment_string = format!(
-            "{comment_string}Target SSIMULACRA 2: Mean: {}\n",
-            args.target_quality
-        );
-    }
-    comment_string = format!(
-        "{comment_string}Encoding settings: {}: \"{}\"",
-        get_encoder_version(args.encoder.clone().as_str()).unwrap(),
-        encoder_options
-    );
-    if opus_options != "" {
-        comment_string = format!(
-            "{comment_string} + opusenc libopus {}: \"{opus_options}\"",
-            get_encoder_version("opusenc").unwrap()
-        );
+    for (name, mut value) in release_info.lines() {
+        if name == "Encoder settings" && opus_options != "" {
+            value = format!(
+                "{value} + opusenc libopus {}: \"{opus_options}\"",
+                get_encoder_version("opusenc", args).unwrap()
+            );
+        }
+        comment_string = format!("{comment_string}{name}: {value}\n");
     }
-    comment_string.push('\n');
-    if !args.no_grain {
-        comment_string = format!(
-            "{comment_string}Film grain synthesis settings: grav1synth: {}\n",
-            get_grain_string(&args)
-        );
+    if !args.no_footer {
+        comment_string.push_str(args.footer.as_str());
+    } else if comment_string.ends_with('\n') {
+        comment_string.pop();
     }
-    if !args.no_filter {
-        comment_string = format!("{comment_string}Filters: {}\n", get_filter_string(&args));
+    let torrent_files = &stage_torrent_files(torrent_files, args);
+    let torrent = build_torrent(comment_string, torrent_files, args);
+    torrent.write_into_file(&torrent_path).unwrap();
+    if args.verify_torrent {
+        verify_torrent(torrent_path, torrent_files);
     }
-    if args.rescale {
-        comment_string = format!("{comment_string}Rescale: {}\n", get_rescale_string(&args));
+    let open = open::that(&torrent_path);
+    if open.is_err() {
+        eprintln_log!("Failed to open {} automatically.", torrent_path.display());
     }
-    comment_string.push_str("Interested in AV1?: https://discord.gg/83dRFDFDp7");
+    println_log!(
+        "Torrent for {} done at {}",
+        torrent_files.display(),
+        torrent_path.display()
+    );
+}
+
+// everything but the creation date comes from the files and the arguments, so --torrent-date makes it reproducible
+fn build_torrent(comment_string: String, torrent_files: &PathBuf, args: &Args) -> Torrent {
     let announce: &'static str = "http://nyaa.tracker.wf:7777/announce";
     let announce_list: [[&'static str; 1]; 11] = [
         ["http://nyaa.tracker.wf:7777/announce"],
@@ -80,9 +136,15 @@ pub fn create_torrent(
         ["http://tracker.gbitt.info:80/announce"],
         ["udp://tracker-udp.gbitt.info:80/announce"],
     ];
-    #[rustfmt::skip]
-    let creation_date = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let name = torrent_files.file_name().unwrap().to_str().unwrap();
+    let creation_date = if args.reproducible {
+        0
+    } else if let Some(date) = args.torrent_date {
+        date
+    } else {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    };
+    let creator = args.torrent_creator.clone().unwrap_or(args.group.clone());
+    let name = path_str(&torrent_files.file_name().unwrap());
     let piece_length = pieces(&torrent_files);
     #[rustfmt::skip]
     let mut torrent_build = TorrentBuilder::new(&torrent_files, piece_length as i64)
@@ -90,23 +152,31 @@ pub fn create_torrent(
         .set_announce_list(announce_list.map(|v| [v[0].to_string()].to_vec()).to_vec())
         .set_name(name.into())
         .add_extra_info_field("private".into(), bInt(0))
-        .add_extra_field("creation date".into(), bInt(creation_date as i64))
-        .add_extra_field("comment".into(), bString(comment_string.clone()))
-        .add_extra_field("created by".into(), bString(args.group.clone()));
+        .add_extra_field("creation date".into(), bInt(creation_date))
+        .add_extra_field("comment".into(), bString(comment_string))
+        .add_extra_field("created by".into(), bString(creator));
     if args.source_url.is_some() {
         torrent_build = torrent_build
             .clone()
             .add_extra_info_field("source".into(), bString(args.source_url.clone().unwrap()));
     }
-    let torrent = torrent_build.build().unwrap();
-    torrent.write_into_file(&torrent_path).unwrap();
-    let open = open::that(&torrent_path);
-    if open.is_err() {
-        eprintln!("Failed to open {} automatically.", torrent_path.display());
+    torrent_build.build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::ScratchDir;
+    use clap::Parser;
+
+    #[test]
+    fn same_torrent_date_gives_identical_torrents() {
+        let scratch = ScratchDir::new("torrent");
+        let file = scratch.0.join("Show - 01.mkv");
+        fs::write(&file, vec![7u8; 100_000]).unwrap();
+        let args = Args::parse_from(["grav1an", "-i", "/tmp", "-o", "/tmp/out", "-n", "Show", "--torrent-date", "1700000000"]);
+        let [first, second] = [0, 1].map(|_| build_torrent("comment".to_string(), &file, &args).encode().unwrap());
+        assert_eq!(first, second);
+        assert!(first.windows(12).any(|w| w == b"i1700000000e"), "the creation date isn't --torrent-date");
     }
-    println!(
-        "Torrent for {} done at {}",
-        torrent_files.display(),
-        torrent_path.display()
-    );
 }